@@ -0,0 +1,91 @@
+//! Parsing throughput of owned and borrowed payloads, and of messages going through
+//! the simulator, run with `cargo bench`.
+
+use std::borrow::Cow;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use dist_sys_challenge::{sim::Network, Init, Message, Node, Output, Payload};
+use serde_json::json;
+
+#[derive(Debug, Payload)]
+enum Owned {
+    Echo { echo: String },
+    Gossip { from: usize, messages: Vec<usize> },
+}
+
+#[derive(Debug, Payload)]
+enum Borrowed<'a> {
+    Echo {
+        #[serde(borrow)]
+        echo: Cow<'a, str>,
+    },
+    Gossip {
+        from: usize,
+        messages: Vec<usize>,
+    },
+}
+
+const ECHO: &str = r#"{"src":"c12","dest":"n3","body":{"type":"echo","msg_id":812,"echo":"Please echo 17 and a bit more text so the payload isn't tiny"}}"#;
+const GOSSIP: &str = r#"{"src":"n7","dest":"n3","body":{"type":"gossip","msg_id":41,"from":1200,"messages":[1201,1202,1203,1204,1205,1206,1207,1208]}}"#;
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(1));
+    for (name, line) in [("echo", ECHO), ("gossip", GOSSIP)] {
+        group.bench_function(format!("{name}/owned"), |b| {
+            b.iter(|| serde_json::from_str::<Message<Owned>>(line).unwrap())
+        });
+        group.bench_function(format!("{name}/borrowed"), |b| {
+            b.iter(|| serde_json::from_str::<Message<Borrowed>>(line).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Counts what it receives, so the time is spent in the simulator and not in the node
+struct Sink {
+    received: usize,
+}
+
+impl Node for Sink {
+    type Msg<'a> = Borrowed<'a>;
+
+    fn new(_: Init, _: Output) -> Self {
+        Self { received: 0 }
+    }
+
+    fn process(&mut self, _: &Message<Self::Msg<'_>>) -> dist_sys_challenge::Result<()> {
+        self.received += 1;
+        Ok(())
+    }
+}
+
+// Routing, queueing and delivery by `sim::Network` on top of parsing, the stdin runtimes
+// aren't measured since they block on their input
+fn simulator(c: &mut Criterion) {
+    const MESSAGES: usize = 1000;
+
+    let mut group = c.benchmark_group("simulator");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("deliver", |b| {
+        b.iter_batched(
+            || {
+                let mut net = Network::new(0);
+                let nodes = net.spawn::<Sink>(1);
+                for i in 0..MESSAGES {
+                    net.send(
+                        &nodes[0],
+                        json!({"type": "gossip", "from": i, "messages": [i, i + 1, i + 2]}),
+                    );
+                }
+                net
+            },
+            |mut net| net.run_until_idle(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, simulator);
+criterion_main!(benches);
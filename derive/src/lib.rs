@@ -0,0 +1,211 @@
+//! `#[derive(Payload)]` for the message enums of `dist-sys-challenge`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, Path, Variant};
+
+/// Implements `Payload`, `Serialize` and `Deserialize` for an enum with maelstrom's
+/// conventions, every variant is a message whose `type` is its name in snake case.
+///
+/// Fields and variants accept `#[serde(...)]` attributes as usual, e.g. `#[serde(borrow)]`
+/// on a `Cow<'a, str>` of a payload that borrows from its input. With
+/// `#[payload(reply = Replies)]` the enum also implements `Request` and every variant `Foo`
+/// must have a `FooOk` counterpart in `Replies`, variants that are replies themselves
+/// (their name ends in `Ok`) or are marked `#[payload(no_reply)]` are exempt.
+#[proc_macro_derive(Payload, attributes(payload, serde))]
+pub fn derive_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    if input.generics.type_params().next().is_some()
+        || input.generics.const_params().next().is_some()
+    {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "payloads can only be generic over lifetimes",
+        ));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let lifetimes = input
+        .generics
+        .lifetimes()
+        .map(|param| &param.lifetime)
+        .collect::<Vec<_>>();
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "payloads have to be enums",
+        ));
+    };
+
+    let mut reply = None;
+    for attr in payload_attrs(&input.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("reply") {
+                reply = Some(meta.value()?.parse::<Path>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `reply = <type>`"))
+            }
+        })?;
+    }
+
+    let mut ser_variants = Vec::new();
+    let mut de_variants = Vec::new();
+    let mut to_mirror = Vec::new();
+    let mut from_mirror = Vec::new();
+    let mut reply_checks = Vec::new();
+
+    for variant in &data.variants {
+        let ident = &variant.ident;
+        let attrs = serde_attrs(&variant.attrs);
+        let Fields::Named(fields) = &variant.fields else {
+            if matches!(variant.fields, Fields::Unit) {
+                ser_variants.push(quote! { #(#attrs)* #ident });
+                de_variants.push(quote! { #(#attrs)* #ident });
+                to_mirror.push(quote! { #name::#ident => __Ser::#ident });
+                from_mirror.push(quote! { __De::#ident => #name::#ident });
+                reply_checks.extend(reply_check(&reply, variant)?);
+                continue;
+            }
+            return Err(syn::Error::new_spanned(
+                variant,
+                "payload variants need named fields, e.g. `Read {}`",
+            ));
+        };
+
+        let field_names = fields
+            .named
+            .iter()
+            .map(|field| field.ident.as_ref().expect("named field"))
+            .collect::<Vec<_>>();
+        let field_attrs = fields
+            .named
+            .iter()
+            .map(|field| serde_attrs(&field.attrs))
+            .collect::<Vec<_>>();
+        let field_types = fields
+            .named
+            .iter()
+            .map(|field| &field.ty)
+            .collect::<Vec<_>>();
+
+        ser_variants.push(quote! {
+            #(#attrs)* #ident { #( #(#field_attrs)* #field_names: &'__ser #field_types, )* }
+        });
+        de_variants.push(quote! {
+            #(#attrs)* #ident { #( #(#field_attrs)* #field_names: #field_types, )* }
+        });
+        to_mirror.push(quote! {
+            #name::#ident { #(#field_names),* } => __Ser::#ident { #(#field_names),* }
+        });
+        from_mirror.push(quote! {
+            __De::#ident { #(#field_names),* } => #name::#ident { #(#field_names),* }
+        });
+        reply_checks.extend(reply_check(&reply, variant)?);
+    }
+
+    let request = reply.as_ref().map(|reply| {
+        quote! {
+            impl #impl_generics ::dist_sys_challenge::Request for #name #ty_generics #where_clause {
+                type Reply = #reply;
+            }
+
+            // fails to compile if a request has no matching reply
+            #[allow(dead_code, unreachable_patterns)]
+            fn __replies #impl_generics (reply: &#reply) {
+                match reply {
+                    #( #reply_checks )*
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    // borrowed fields live as long as the input they were deserialized from
+    let de = if lifetimes.is_empty() {
+        quote! { '__de }
+    } else {
+        quote! { '__de: #(#lifetimes)+* }
+    };
+
+    Ok(quote! {
+        const _: () = {
+            #[allow(clippy::enum_variant_names)]
+            #[derive(::serde::Serialize)]
+            #[serde(tag = "type", rename_all = "snake_case")]
+            enum __Ser<'__ser, #(#lifetimes),*> {
+                #(#ser_variants,)*
+                #[serde(skip)]
+                __Phantom(::std::marker::PhantomData<&'__ser ()>),
+            }
+
+            #[allow(clippy::enum_variant_names)]
+            #[derive(::serde::Deserialize)]
+            #[serde(tag = "type", rename_all = "snake_case")]
+            enum __De<#(#lifetimes),*> {
+                #(#de_variants,)*
+            }
+
+            impl #impl_generics ::serde::Serialize for #name #ty_generics #where_clause {
+                fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                    let mirror = match self {
+                        #(#to_mirror,)*
+                    };
+                    ::serde::Serialize::serialize(&mirror, serializer)
+                }
+            }
+
+            impl<#de, #(#lifetimes),*> ::serde::Deserialize<'__de> for #name #ty_generics #where_clause {
+                fn deserialize<D: ::serde::Deserializer<'__de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+                    Ok(match <__De<#(#lifetimes),*> as ::serde::Deserialize<'__de>>::deserialize(deserializer)? {
+                        #(#from_mirror,)*
+                    })
+                }
+            }
+
+            impl #impl_generics ::dist_sys_challenge::Payload for #name #ty_generics #where_clause {}
+
+            #request
+        };
+    })
+}
+
+fn payload_attrs(attrs: &[Attribute]) -> impl Iterator<Item = &Attribute> {
+    attrs.iter().filter(|attr| attr.path().is_ident("payload"))
+}
+
+fn serde_attrs(attrs: &[Attribute]) -> Vec<&Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+        .collect()
+}
+
+fn reply_check(reply: &Option<Path>, variant: &Variant) -> syn::Result<Option<TokenStream2>> {
+    let Some(reply) = reply else {
+        return Ok(None);
+    };
+    let mut no_reply = false;
+    for attr in payload_attrs(&variant.attrs) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("no_reply") {
+                no_reply = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `no_reply`"))
+            }
+        })?;
+    }
+    if no_reply || variant.ident.to_string().ends_with("Ok") {
+        return Ok(None);
+    }
+    let ok = format_ident!("{}Ok", variant.ident, span = variant.ident.span());
+    Ok(Some(quote! { #reply::#ok { .. } => {} }))
+}
@@ -0,0 +1,172 @@
+//! Coalescing of outbound messages, payloads queued for the same destination within
+//! a window are merged into one message by a user provided hook.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    event_loop::{Context, Timer},
+    EventNode, Message, MsgId, NodeId, Payload, Result,
+};
+
+/// Queues payloads per destination and sends them when its timer fires,
+/// the node has to call [`Batcher::flush`] from `on_tick` for that timer.
+pub struct Batcher<P> {
+    timer: &'static str,
+    window: Duration,
+    pending: BTreeMap<NodeId, P>,
+    merge: fn(&mut P, P),
+}
+
+impl<P> Batcher<P>
+where
+    P: Payload,
+    Message<P>: Serialize,
+{
+    /// `merge` folds a newly queued payload into the one already pending for a destination.
+    pub fn new(timer: &'static str, window: Duration, merge: fn(&mut P, P)) -> Self {
+        Self {
+            timer,
+            window,
+            pending: BTreeMap::new(),
+            merge,
+        }
+    }
+
+    /// Name of the timer the batcher sets, to tell it apart in `on_tick`.
+    pub fn timer(&self) -> &'static str {
+        self.timer
+    }
+
+    /// Queue `payload` for `dest`, the first payload queued after a flush starts the window.
+    /// With a zero window everything queued while handling one event goes out together.
+    pub fn push<N: EventNode>(&mut self, ctx: &mut Context<N>, dest: NodeId, payload: P) {
+        if self.pending.is_empty() {
+            ctx.set_timer(Timer::once(self.timer, self.window));
+        }
+        match self.pending.get_mut(&dest) {
+            Some(pending) => (self.merge)(pending, payload),
+            None => {
+                self.pending.insert(dest, payload);
+            }
+        }
+    }
+
+    /// Send everything queued, one message per destination.
+    pub fn flush<N: EventNode>(&mut self, ctx: &mut Context<N>, msg_id: &mut MsgId) -> Result<()> {
+        ctx.cancel_timer(self.timer);
+        for (dest, payload) in std::mem::take(&mut self.pending) {
+            let msg = Message::new(ctx.node_id().clone(), dest, Some(msg_id), payload);
+            ctx.send(msg)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{sim::Network, Init, Output};
+
+    // Queues the values it is told to push, the nodes they are pushed to keep every batch
+    struct Batching {
+        msg_seq_id: MsgId,
+        batch: Batcher<Batch>,
+        received: Vec<Vec<usize>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        Push { to: NodeId, values: Vec<usize> },
+        Values { values: Vec<usize> },
+        Received {},
+    }
+
+    impl Payload for Request {}
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Reply {
+        PushOk {},
+        ReceivedOk { batches: Vec<Vec<usize>> },
+    }
+
+    impl Payload for Reply {}
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Batch {
+        Values { values: Vec<usize> },
+    }
+
+    impl Payload for Batch {}
+
+    fn merge(Batch::Values { values }: &mut Batch, Batch::Values { values: more }: Batch) {
+        values.extend(more);
+    }
+
+    impl EventNode for Batching {
+        type Msg<'a> = Request;
+
+        fn new(_: Init, _: Output) -> Self {
+            Self {
+                msg_seq_id: MsgId::ONE,
+                batch: Batcher::new("batch", Duration::from_secs(1), merge),
+                received: Vec::new(),
+            }
+        }
+
+        fn on_message(
+            &mut self,
+            ctx: &mut Context<Self>,
+            request: &Message<Request>,
+        ) -> Result<()> {
+            let reply = match request.payload() {
+                Request::Push { to, values } => {
+                    let values = values.clone();
+                    self.batch.push(ctx, to.clone(), Batch::Values { values });
+                    Reply::PushOk {}
+                }
+                Request::Values { values } => {
+                    self.received.push(values.clone());
+                    return Ok(());
+                }
+                Request::Received {} => Reply::ReceivedOk {
+                    batches: self.received.clone(),
+                },
+            };
+            ctx.respond(request, Some(&mut self.msg_seq_id), reply)
+        }
+
+        fn on_tick(&mut self, ctx: &mut Context<Self>, _timer: &str) -> Result<()> {
+            self.batch.flush(ctx, &mut self.msg_seq_id)
+        }
+    }
+
+    #[test]
+    fn coalesced() {
+        let received = |net: &mut Network, node| {
+            net.call(node, json!({"type": "received"})).payload()["batches"].clone()
+        };
+
+        let mut net = Network::new(0);
+        let nodes = net.spawn::<Batching>(3);
+        for (to, values) in [(1, [1, 2]), (2, [3, 4]), (1, [5, 6]), (1, [7, 8])] {
+            let push = json!({"type": "push", "to": nodes[to], "values": values});
+            net.call(&nodes[0], push);
+        }
+        // nothing goes out before the window is over
+        assert_eq!(received(&mut net, &nodes[1]), json!([]));
+
+        let sent = net.messages_sent();
+        net.run_for(Duration::from_secs(1));
+        assert_eq!(net.messages_sent(), sent + 2);
+        assert_eq!(received(&mut net, &nodes[1]), json!([[1, 2, 5, 6, 7, 8]]));
+        assert_eq!(received(&mut net, &nodes[2]), json!([[3, 4]]));
+    }
+}
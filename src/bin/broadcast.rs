@@ -1,23 +1,60 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::stdout,
-    sync::mpsc::{Receiver, Sender},
+    collections::{BTreeMap, HashMap, HashSet},
     time::Duration,
 };
 
-use dist_sys_challenge::{Init, Message, MsgId, Node, NodeId, Payload};
-use serde::{Deserialize, Serialize};
+use dist_sys_challenge::{
+    batch::Batcher,
+    event_loop::{Context, Event, Timer},
+    topology::Strategy,
+    EventNode, Init, Message, MsgId, NodeId, Output, Payload,
+};
 
-fn main() -> std::io::Result<()> {
-    dist_sys_challenge::run::<BroadcastNode>()
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // e.g. `BROADCAST_TOPOLOGY=tree:4`, see `Strategy::from_str` for the options
+    let strategy = std::env::var("BROADCAST_TOPOLOGY")
+        .ok()
+        .map(|strategy| strategy.parse::<Strategy>())
+        .transpose()?;
+    dist_sys_challenge::event_loop::run_with(|init, output| {
+        BroadcastNode::with_strategy(init, output, strategy)
+    })?;
+    Ok(())
 }
 
 struct BroadcastNode {
-    processor: Sender<Action>,
+    node_id: NodeId,
+    neighbors: Vec<NodeId>,
+    // when set the overlay is computed from `node_ids` and `topology` messages are ignored
+    strategy: Option<Strategy>,
+    msg_seq_id: MsgId,
+    seen: HashSet<usize>,
+    // values in the order we learned them and the neighbor they came from,
+    // gossip refers to ranges of this log instead of individual values
+    log: Vec<(usize, Option<NodeId>)>,
+    peers: HashMap<NodeId, Peer>,
+    // gossip needs no batcher, each tick already sends one message per neighbor with
+    // everything new in the log, while an ack answers each gossip message on its own
+    acks: Batcher<Ack>,
+    gossip_interval: Duration,
+    max_backoff: Duration,
+    output: Output,
+}
+
+// What a neighbor has of our log, everything before `acked` is confirmed
+// and everything before `sent` was sent at least once.
+#[derive(Debug, Clone)]
+struct Peer {
+    acked: usize,
+    // ranges acknowledged out of order, merged into `acked` once the gap closes
+    ranges: BTreeMap<usize, usize>,
+    sent: usize,
+    due: Duration,
+    backoff: Duration,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
 enum RequestMessages {
     Broadcast {
         message: usize,
@@ -26,152 +63,394 @@ enum RequestMessages {
     Topology {
         topology: HashMap<NodeId, Vec<NodeId>>,
     },
+    #[payload(no_reply)]
     Gossip {
-        news: Vec<News>,
+        // offsets in the sender's log covered by `messages`
+        from: usize,
+        to: usize,
+        messages: Vec<usize>,
+    },
+    GossipOk {
+        ranges: Vec<(usize, usize)>,
     },
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-enum News {
-    // sender knows but belives we don't know
-    NewValue(usize),
-    // sender knwows we know, but we have send them recently (we didn't knew they knew)
-    VerifiedValue(usize),
+#[derive(Debug, Payload)]
+enum ResponseMessages {
+    BroadcastOk {},
+    ReadOk {
+        messages: Vec<usize>,
+    },
+    TopologyOk {},
+    Gossip {
+        from: usize,
+        to: usize,
+        messages: Vec<usize>,
+    },
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Knowledge {
-    // We know they know (received from them), but they don't know we know
-    ToBeConfirmed,
-    // We know they know and they know we know
-    Confirmed,
+// acknowledgements are batched per neighbor
+#[derive(Debug, Payload)]
+enum Ack {
+    GossipOk { ranges: Vec<(usize, usize)> },
 }
 
-impl Payload for RequestMessages {}
+fn merge_acks(Ack::GossipOk { ranges }: &mut Ack, Ack::GossipOk { ranges: more }: Ack) {
+    ranges.extend(more);
+}
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponseMessages {
-    BroadcastOk {},
-    ReadOk { messages: Vec<usize> },
-    TopologyOk {},
-    // See RequestMessages
-    Gossip { news: Vec<News> },
+impl Peer {
+    // returns whether the high-water mark moved
+    fn ack(&mut self, from: usize, to: usize) -> bool {
+        let acked = self.acked;
+        if from > self.acked {
+            let end = self.ranges.entry(from).or_default();
+            *end = (*end).max(to);
+            return false;
+        }
+        self.acked = self.acked.max(to);
+        while let Some(entry) = self.ranges.first_entry() {
+            if *entry.key() > self.acked {
+                break;
+            }
+            self.acked = self.acked.max(entry.remove());
+        }
+        self.acked > acked
+    }
 }
 
-impl Payload for ResponseMessages {}
+impl BroadcastNode {
+    fn with_strategy(
+        Init::Init { node_id, node_ids }: Init,
+        output: Output,
+        strategy: Option<Strategy>,
+    ) -> Self {
+        let neighbors = match strategy {
+            Some(strategy) => strategy.neighbors(&node_id, &node_ids),
+            None => node_ids
+                .into_iter()
+                .filter(|neighbor| *neighbor != node_id)
+                .collect(),
+        };
+        Self {
+            node_id,
+            neighbors,
+            strategy,
+            msg_seq_id: MsgId::ONE,
+            seen: HashSet::new(),
+            log: Vec::new(),
+            peers: HashMap::new(),
+            acks: Batcher::new("acks", Duration::from_millis(25), merge_acks),
+            gossip_interval: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            output,
+        }
+    }
+
+    fn learn(&mut self, value: usize, from: &NodeId) {
+        if self.seen.insert(value) {
+            let from = self.neighbors.contains(from).then(|| from.clone());
+            self.log.push((value, from));
+        }
+    }
 
-enum Action {
-    Msg(Message<RequestMessages>),
-    Gossip,
-}
+    fn peer(&mut self, neighbor: &NodeId) -> &mut Peer {
+        self.peers.entry(neighbor.clone()).or_insert(Peer {
+            acked: 0,
+            ranges: BTreeMap::new(),
+            sent: 0,
+            due: Duration::ZERO,
+            backoff: self.gossip_interval,
+        })
+    }
 
-fn processor(
-    Init::Init {
-        node_id,
-        node_ids: mut neighbors,
-    }: Init,
-    channel: Receiver<Action>,
-) -> std::io::Result<()> {
-    let mut msg_seq_id = MsgId::ONE;
-    let mut seen = HashSet::<usize>::new();
-    let mut knowledge = HashMap::<(NodeId, usize), Knowledge>::new();
-
-    for action in channel {
-        match action {
-            Action::Msg(request) => match request.payload() {
-                RequestMessages::Broadcast { message } => {
-                    seen.insert(*message);
-                    request.respond(
-                        &mut stdout(),
-                        Some(&mut msg_seq_id),
-                        ResponseMessages::BroadcastOk {},
-                    )?;
-                }
-                RequestMessages::Read {} => {
-                    request.respond(
-                        &mut stdout(),
-                        Some(&mut msg_seq_id),
-                        ResponseMessages::ReadOk {
-                            messages: seen.iter().copied().collect(),
-                        },
-                    )?;
+    fn gossip(&mut self, neighbor: &NodeId, from: usize) -> dist_sys_challenge::Result<()> {
+        let to = self.log.len();
+        // values the neighbor sent us are skipped but still count as covered
+        let messages = self.log[from..to]
+            .iter()
+            .filter(|(_, source)| source.as_ref() != Some(neighbor))
+            .map(|(value, _)| *value)
+            .collect::<Vec<_>>();
+        if messages.is_empty() {
+            self.peer(neighbor).ack(from, to);
+            return Ok(());
+        }
+        Message::new(
+            self.node_id.clone(),
+            neighbor.to_owned(),
+            Some(&mut self.msg_seq_id),
+            ResponseMessages::Gossip { from, to, messages },
+        )
+        .send(&mut self.output)?;
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<RequestMessages>,
+    ) -> dist_sys_challenge::Result<()> {
+        match request.payload() {
+            RequestMessages::Broadcast { message } => {
+                self.learn(*message, request.src());
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::BroadcastOk {},
+                )?;
+            }
+            RequestMessages::Read {} => {
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::ReadOk {
+                        messages: self.seen.iter().copied().collect(),
+                    },
+                )?;
+            }
+            RequestMessages::Topology { topology } => {
+                let given = topology
+                    .get(&self.node_id)
+                    .filter(|_| self.strategy.is_none());
+                if let Some(new_neighbors) = given {
+                    // new neighbors start from the beginning of the log
+                    self.neighbors = new_neighbors.clone();
+                    self.peers
+                        .retain(|neighbor, _| new_neighbors.contains(neighbor));
                 }
-                RequestMessages::Topology { topology } => {
-                    topology
-                        .get(&node_id)
-                        .cloned()
-                        .map(|new_neighbors| neighbors = new_neighbors);
-                    request.respond(
-                        &mut stdout(),
-                        Some(&mut msg_seq_id),
-                        ResponseMessages::TopologyOk {},
-                    )?;
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::TopologyOk {},
+                )?;
+            }
+            RequestMessages::Gossip { from, to, messages } => {
+                for value in messages {
+                    self.learn(*value, request.src());
                 }
-                RequestMessages::Gossip { news } => {
-                    news.iter().for_each(|news| {
-                        let (val, kind) = match news {
-                            News::NewValue(val) => (*val, Knowledge::ToBeConfirmed),
-                            News::VerifiedValue(val) => (*val, Knowledge::Confirmed),
-                        };
-                        seen.insert(val);
-                        knowledge.insert((request.src().to_owned(), val), kind);
-                    });
+                self.acks.push(
+                    ctx,
+                    request.src().clone(),
+                    Ack::GossipOk {
+                        ranges: vec![(*from, *to)],
+                    },
+                );
+            }
+            RequestMessages::GossipOk { ranges } => {
+                let gossip_interval = self.gossip_interval;
+                let peer = self.peer(request.src());
+                let mut progress = false;
+                for &(from, to) in ranges {
+                    progress |= peer.ack(from, to);
                 }
-            },
-            Action::Gossip => {
-                for neighbor in &neighbors {
-                    let news = seen
-                        .iter()
-                        .copied()
-                        .filter_map(|value| match knowledge.get(&(neighbor.clone(), value)) {
-                            Some(Knowledge::ToBeConfirmed) => Some(News::VerifiedValue(value)),
-                            Some(Knowledge::Confirmed) => None,
-                            None => Some(News::NewValue(value)),
-                        })
-                        .collect::<Vec<_>>();
-
-                    if !news.is_empty() {
-                        Message::new(
-                            node_id.clone(),
-                            neighbor.to_owned(),
-                            Some(&mut msg_seq_id),
-                            ResponseMessages::Gossip { news },
-                        )
-                        .send(&mut stdout())?;
-                    }
+                if progress {
+                    peer.backoff = gossip_interval;
                 }
             }
         }
+        Ok(())
     }
-
-    Ok(())
 }
 
-impl Node for BroadcastNode {
-    type Msg = RequestMessages;
+impl EventNode for BroadcastNode {
+    type Msg<'a> = RequestMessages;
+
+    fn new(init: Init, output: Output) -> Self {
+        Self::with_strategy(init, output, None)
+    }
+
+    fn start(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        ctx.set_timer(
+            Timer::every("gossip", self.gossip_interval).with_jitter(self.gossip_interval / 5),
+        );
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        self.handle(ctx, request)
+    }
 
-    fn new(init: dist_sys_challenge::Init) -> Self {
-        let (sender, receiver) = std::sync::mpsc::channel();
+    fn on_event(
+        &mut self,
+        ctx: &mut Context<Self>,
+        event: &Event<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        match event {
+            Event::Message(request) | Event::Reply(request) => self.handle(ctx, request),
+            Event::Timer(timer) if *timer == self.acks.timer() => {
+                self.acks.flush(ctx, &mut self.msg_seq_id)
+            }
+            Event::Timer(timer) => self.on_tick(ctx, timer),
+        }
+    }
 
-        let sender_clone = sender.clone();
-        std::thread::spawn(|| processor(init, receiver));
-        std::thread::spawn(move || -> std::io::Result<()> {
-            loop {
-                std::thread::sleep(Duration::from_millis(50));
-                sender_clone
-                    .send(Action::Gossip)
-                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))?;
+    fn on_tick(&mut self, ctx: &mut Context<Self>, _timer: &str) -> dist_sys_challenge::Result<()> {
+        let now = ctx.now();
+        for neighbor in self.neighbors.clone() {
+            let len = self.log.len();
+            let max_backoff = self.max_backoff;
+            let peer = self.peer(&neighbor);
+            if peer.acked >= len {
+                continue;
             }
-        });
 
-        Self { processor: sender }
+            // new values go out right away, unacknowledged ones are resent with
+            // exponential backoff so a partitioned neighbor catches up once it heals
+            let from = if peer.due <= now {
+                peer.due = now + peer.backoff;
+                peer.backoff = (peer.backoff * 2).min(max_backoff);
+                peer.acked
+            } else if peer.sent < len {
+                peer.sent
+            } else {
+                continue;
+            };
+            peer.sent = len;
+            self.gossip(&neighbor, from)?;
+        }
+        Ok(())
     }
 
-    fn process(&mut self, request: &dist_sys_challenge::Message<Self::Msg>) -> std::io::Result<()> {
-        self.processor
-            .send(Action::Msg(request.clone()))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))?;
+    fn shutdown(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        // one last attempt for everything that isn't acknowledged yet
+        self.acks.flush(ctx, &mut self.msg_seq_id)?;
+        for neighbor in self.neighbors.clone() {
+            let acked = self.peer(&neighbor).acked;
+            if acked < self.log.len() {
+                self.gossip(&neighbor, acked)?;
+            }
+        }
         Ok(())
     }
 }
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<BroadcastNode>(5);
+    for (i, node) in nodes.iter().enumerate() {
+        let reply = net.call(node, json!({"type": "broadcast", "message": i}));
+        assert_eq!(reply.payload()["type"], "broadcast_ok");
+    }
+    net.run_for(Duration::from_secs(1));
+
+    for node in &nodes {
+        assert_eq!(read(&mut net, node), (0..nodes.len()).collect::<Vec<_>>());
+    }
+
+    // once everything is acknowledged gossip stops
+    let sent = net.messages_sent();
+    net.run_for(Duration::from_secs(5));
+    assert_eq!(net.messages_sent(), sent);
+}
+
+#[test]
+fn shutdown() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<BroadcastNode>(3);
+    net.call(&nodes[0], json!({"type": "broadcast", "message": 7}));
+    // stopped before its first gossip tick
+    net.stop(&nodes[0]);
+    net.run_for(Duration::from_secs(1));
+
+    for node in &nodes[1..] {
+        let reply = net.call(node, json!({"type": "read"}));
+        assert_eq!(reply.payload()["messages"], json!([7]));
+    }
+}
+
+#[test]
+fn partitioned() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<BroadcastNode>(5);
+    net.partition(&[&nodes[..2], &nodes[2..]]);
+    for (i, node) in nodes.iter().enumerate() {
+        let reply = net.call(node, json!({"type": "broadcast", "message": i}));
+        assert_eq!(reply.payload()["type"], "broadcast_ok");
+    }
+    net.run_for(Duration::from_secs(5));
+    assert_eq!(read(&mut net, &nodes[0]), [0, 1]);
+    assert_eq!(read(&mut net, &nodes[4]), [2, 3, 4]);
+
+    net.heal();
+    net.run_for(Duration::from_secs(2));
+    for node in &nodes {
+        assert_eq!(read(&mut net, node), (0..nodes.len()).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn strategies() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    for strategy in [
+        Strategy::FullMesh,
+        Strategy::Grid,
+        Strategy::SpanningTree,
+        Strategy::HubAndSpoke,
+        Strategy::KaryTree(4),
+        Strategy::RandomRegular(3),
+    ] {
+        // the way `main` starts the nodes for `BROADCAST_TOPOLOGY`
+        let mut net = Network::new(0);
+        let nodes = net.spawn_with(25, |init, output| {
+            BroadcastNode::with_strategy(init, output, Some(strategy))
+        });
+        let topology = strategy.topology(&nodes);
+        for (node, neighbors) in &topology {
+            for neighbor in neighbors {
+                assert!(
+                    topology[neighbor].contains(node),
+                    "{strategy:?} is not symmetric"
+                );
+            }
+        }
+        // ignored, the strategy decides
+        let mesh = Strategy::FullMesh.topology(&nodes);
+        net.call(&nodes[0], json!({"type": "topology", "topology": mesh}));
+        net.drop_if(move |msg| {
+            assert!(
+                msg.payload()["type"] != "gossip" || topology[msg.src()].contains(msg.dst()),
+                "{strategy:?} gossiped outside of the overlay"
+            );
+            false
+        });
+        for (i, node) in nodes.iter().enumerate() {
+            net.call(node, json!({"type": "broadcast", "message": i}));
+        }
+        net.run_for(Duration::from_secs(1));
+
+        for node in &nodes {
+            assert_eq!(
+                read(&mut net, node).len(),
+                nodes.len(),
+                "{strategy:?} did not converge"
+            );
+        }
+        // gossip only goes to the neighbors of the overlay
+    }
+}
+
+#[cfg(test)]
+fn read(net: &mut dist_sys_challenge::sim::Network, node: &NodeId) -> Vec<usize> {
+    let reply = net.call(node, serde_json::json!({"type": "read"}));
+    let mut messages: Vec<usize> =
+        serde_json::from_value(reply.payload()["messages"].clone()).unwrap();
+    messages.sort();
+    messages
+}
@@ -1,52 +1,48 @@
-use std::io::stdout;
+use std::borrow::Cow;
 
-use dist_sys_challenge::{MsgId, Node, Payload};
-use serde::{Deserialize, Serialize};
+use dist_sys_challenge::{
+    handler::{Handler, Responder},
+    Message, Payload,
+};
 
-fn main() -> std::io::Result<()> {
-    dist_sys_challenge::run::<EchoNode>()
+fn main() -> dist_sys_challenge::Result<()> {
+    dist_sys_challenge::run::<Responder<EchoNode>>()
 }
 
-struct EchoNode {
-    msg_seq_id: MsgId,
-}
+struct EchoNode;
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum EchoMessages {
-    Echo { echo: String },
+#[derive(Debug, Payload)]
+#[payload(reply = EchoOkMessage)]
+enum EchoMessages<'a> {
+    // borrowed from the input line unless it contains escapes
+    Echo {
+        #[serde(borrow)]
+        echo: Cow<'a, str>,
+    },
 }
 
-impl Payload for EchoMessages {}
-
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Debug, PartialEq, Payload)]
 enum EchoOkMessage {
     EchoOk { echo: String },
 }
 
-impl Payload for EchoOkMessage {}
-
-impl Node for EchoNode {
-    type Msg = EchoMessages;
+impl Handler for EchoNode {
+    type Request<'a> = EchoMessages<'a>;
+    type Reply = EchoOkMessage;
 
     fn new(_: dist_sys_challenge::Init) -> Self {
-        Self {
-            msg_seq_id: MsgId::ONE,
-        }
+        Self
     }
 
-    fn process(&mut self, request: &dist_sys_challenge::Message<Self::Msg>) -> std::io::Result<()> {
+    fn handle(
+        &mut self,
+        request: &Message<Self::Request<'_>>,
+    ) -> dist_sys_challenge::Result<Self::Reply> {
         match request.payload() {
-            EchoMessages::Echo { echo } => {
-                request.respond(
-                    &mut stdout(),
-                    Some(&mut self.msg_seq_id),
-                    EchoOkMessage::EchoOk { echo: echo.clone() },
-                )?;
-            }
+            EchoMessages::Echo { echo } => Ok(EchoOkMessage::EchoOk {
+                echo: echo.to_string(),
+            }),
         }
-        Ok(())
     }
 }
 
@@ -58,3 +54,56 @@ fn deserialize() {
         r#"{"id":2,"src":"c2","dest":"n0","body":{"echo":"Please echo 15","type":"echo","msg_id":1}}"#,
     ).expect("should be a valid message");
 }
+
+#[test]
+fn borrowed() {
+    let request = serde_json::from_str::<Message<EchoMessages>>(
+        r#"{"src":"c1","dest":"n0","body":{"echo":"plain","type":"echo"}}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        request.payload(),
+        EchoMessages::Echo {
+            echo: Cow::Borrowed("plain")
+        }
+    ));
+
+    // escaped strings have to be copied
+    let request = serde_json::from_str::<Message<EchoMessages>>(
+        r#"{"src":"c1","dest":"n0","body":{"echo":"a\nb","type":"echo"}}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        request.payload(),
+        EchoMessages::Echo {
+            echo: Cow::Owned(_)
+        }
+    ));
+}
+
+#[test]
+fn handle() {
+    let request = serde_json::from_str::<Message<EchoMessages>>(
+        r#"{"src":"c1","dest":"n0","body":{"echo":"Please echo 7","type":"echo","msg_id":1}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        EchoNode.handle(&request).unwrap(),
+        EchoOkMessage::EchoOk {
+            echo: "Please echo 7".to_owned()
+        }
+    );
+}
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<Responder<EchoNode>>(1);
+    let reply = net.call(&nodes[0], json!({"type": "echo", "echo": "Please echo 42"}));
+    assert_eq!(reply.payload()["type"], "echo_ok");
+    assert_eq!(reply.payload()["echo"], "Please echo 42");
+    assert_eq!(reply.in_response_to(), Some(dist_sys_challenge::MsgId::ONE));
+}
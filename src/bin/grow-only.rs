@@ -1,229 +1,323 @@
-use std::{
-    collections::HashMap,
-    io::stdout,
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
 
-use dist_sys_challenge::{ErrorCode, Init, Message, MsgId, Node, NodeId, Payload};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use dist_sys_challenge::{
+    crdt::GCounter,
+    event_loop::{Context, Timer},
+    gossip::{GossipNode, Operations},
+    kv::{Kv, KvError},
+    EventNode, Init, MsgId, NodeId, Output, Payload,
+};
 
-fn main() -> std::io::Result<()> {
-    dist_sys_challenge::run::<GrowOnlyNode>()
+fn main() -> dist_sys_challenge::Result<()> {
+    // `GROW_ONLY_MODE=crdt` gossips a g-counter between the nodes instead of using seq-kv
+    match std::env::var("GROW_ONLY_MODE").ok().as_deref() {
+        None | Some("seq-kv") => dist_sys_challenge::event_loop::run::<GrowOnlyNode>(),
+        Some("crdt") => dist_sys_challenge::event_loop::run::<GossipNode<Counter>>(),
+        Some(mode) => panic!("unknown mode `{mode}`, expected seq-kv or crdt"),
+    }
 }
 
+// Every node only writes its own total to `counter/{node_id}` and reads the keys of the
+// others. Writing the total instead of adding a delta makes retries harmless: a cas that
+// timed out may or may not have landed, either way the next one carries the whole total.
 struct GrowOnlyNode {
-    processor: Sender<Action>,
+    node_id: NodeId,
+    peers: Vec<NodeId>,
+    msg_seq_id: MsgId,
+    // adds this node accepted
+    total: usize,
+    // what our key held after our last cas, a failed cas reads it again
+    stored: Option<usize>,
+    committing: bool,
+    // the newest total read from each peer's key
+    read: HashMap<NodeId, usize>,
+    commit_interval: Duration,
+    seq_kv: Kv<String, usize>,
+    output: Output,
+}
+
+fn counter_key(node: &NodeId) -> String {
+    format!("counter/{node}")
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
 enum RequestMessages {
     Add { delta: usize },
     Read {},
-    ReadOk { value: serde_json::Value },
-    CasOk {},
-    Error { code: ErrorCode, text: String },
 }
 
-impl Payload for RequestMessages {}
-
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Debug, Payload)]
 enum ResponseMessages {
     AddOk {},
     ReadOk { value: usize },
 }
 
-impl Payload for ResponseMessages {}
-
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum SeqKVRequest {
-    Read {
-        key: serde_json::Value,
-    },
-    Cas {
-        key: serde_json::Value,
-        from: serde_json::Value,
-        to: serde_json::Value,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        create_if_not_exists: Option<bool>,
-    },
-}
-impl Payload for SeqKVRequest {}
-
-enum Action {
-    Commit,
-    Msg(Message<RequestMessages>),
-}
-
-enum Pending {
-    Write { delta: usize, target: usize },
-}
-
-fn processor(
-    Init::Init {
-        node_id,
-        node_ids: _,
-    }: Init,
-    channel: Receiver<Action>,
-) -> std::io::Result<()> {
-    let mut msg_seq_id = MsgId::ONE;
-    let mut cur_delta = 0;
-    let mut last_read = 0;
-    let mut pending = HashMap::<MsgId, Pending>::new();
-
-    for action in channel {
-        match action {
-            Action::Commit => {
-                Message::new(
-                    node_id.clone(),
-                    NodeId::seq_kv(),
-                    Some(&mut msg_seq_id),
-                    SeqKVRequest::Read {
-                        key: serde_json::Value::String("counter".to_owned()),
-                    },
-                )
-                .send(&mut stdout())?;
-            }
-            Action::Msg(in_msg) => match in_msg.payload() {
-                RequestMessages::Add { delta } => {
-                    cur_delta += delta;
-                    in_msg.respond(
-                        &mut stdout(),
-                        Some(&mut msg_seq_id),
-                        ResponseMessages::AddOk {},
-                    )?;
-                }
-                RequestMessages::Read {} => {
-                    in_msg.respond(
-                        &mut stdout(),
-                        Some(&mut msg_seq_id),
-                        ResponseMessages::ReadOk { value: last_read },
-                    )?;
-                }
-                RequestMessages::ReadOk { value } => {
-                    let value = value.as_u64().unwrap() as usize;
-                    last_read = last_read.max(value);
-
-                    if cur_delta != 0 {
-                        let old = last_read;
-                        let new = last_read + cur_delta;
-
-                        let out_msg = Message::new(
-                            node_id.clone(),
-                            NodeId::seq_kv(),
-                            Some(&mut msg_seq_id),
-                            SeqKVRequest::Cas {
-                                key: serde_json::Value::String("counter".to_owned()),
-                                from: json!(old),
-                                to: json!(new),
-                                create_if_not_exists: (old == 0).then_some(true),
-                            },
-                        );
-
-                        pending.insert(
-                            out_msg.id().unwrap(),
-                            Pending::Write {
-                                delta: cur_delta,
-                                target: new,
-                            },
-                        );
-
-                        cur_delta = 0;
-
-                        out_msg.send(&mut stdout())?;
+impl GrowOnlyNode {
+    fn read(&mut self, ctx: &mut Context<Self>, node: NodeId) -> dist_sys_challenge::Result<()> {
+        self.seq_kv.read(
+            ctx,
+            &mut self.msg_seq_id,
+            counter_key(&node),
+            move |this: &mut Self, _ctx, result| match result {
+                Ok(value) => {
+                    if node == this.node_id {
+                        this.stored = Some(value);
+                    } else {
+                        let read = this.read.entry(node).or_default();
+                        *read = (*read).max(value);
                     }
+                    Ok(())
                 }
-                RequestMessages::CasOk {} => {
-                    if let Some(Pending::Write { delta: _, target }) =
-                        pending.remove(&in_msg.in_response_to().unwrap())
-                    {
-                        last_read = last_read.max(target)
+                Err(KvError::KeyDoesNotExist) => {
+                    if node == this.node_id {
+                        this.stored = Some(0);
                     }
+                    Ok(())
                 }
-                RequestMessages::Error { code, text: _ } => match code {
-                    ErrorCode::PreConditionFailed => {
-                        if let Some(Pending::Write { delta, target: _ }) =
-                            pending.remove(&in_msg.in_response_to().unwrap())
-                        {
-                            cur_delta += delta;
-
-                            // cas failed return delta to cur_delta and read current value
-                            Message::new(
-                                node_id.clone(),
-                                NodeId::seq_kv(),
-                                Some(&mut msg_seq_id),
-                                SeqKVRequest::Read {
-                                    key: serde_json::Value::String("counter".to_owned()),
-                                },
-                            )
-                            .send(&mut stdout())?;
-                        }
+                // the next commit tick reads again
+                Err(KvError::Timeout) => Ok(()),
+                Err(err) => Err(err.into()),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn commit(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        let Some(stored) = self.stored else {
+            return self.read(ctx, self.node_id.clone());
+        };
+        if self.committing || stored >= self.total {
+            return Ok(());
+        }
+        self.committing = true;
+        let total = self.total;
+
+        self.seq_kv.cas(
+            ctx,
+            &mut self.msg_seq_id,
+            counter_key(&self.node_id),
+            stored,
+            total,
+            stored == 0,
+            move |node: &mut Self, _ctx, result| {
+                node.committing = false;
+                match result {
+                    Ok(()) => {
+                        node.stored = Some(total);
+                        Ok(())
                     }
-                    ErrorCode::KeyDoesNotExist => {
-                        // counter not yet initialized
-                        let out_msg = Message::new(
-                            node_id.clone(),
-                            NodeId::seq_kv(),
-                            Some(&mut msg_seq_id),
-                            SeqKVRequest::Cas {
-                                key: serde_json::Value::String("counter".to_owned()),
-                                from: json!(0),
-                                to: json!(cur_delta),
-                                create_if_not_exists: Some(true),
-                            },
-                        );
-
-                        pending.insert(
-                            out_msg.id().unwrap(),
-                            Pending::Write {
-                                delta: cur_delta,
-                                target: cur_delta,
-                            },
-                        );
-
-                        cur_delta = 0;
-
-                        out_msg.send(&mut stdout())?;
+                    // an earlier cas landed after all, the next tick reads our key first
+                    Err(KvError::PreConditionFailed) => {
+                        node.stored = None;
+                        Ok(())
                     }
-                    _ => unimplemented!("Unexpected Error"),
-                },
+                    // the cas may still land, then the next one fails and we read again
+                    Err(KvError::Timeout) => Ok(()),
+                    Err(err) => Err(err.into()),
+                }
             },
-        }
+        )?;
+        Ok(())
     }
-
-    Ok(())
 }
 
-impl Node for GrowOnlyNode {
-    type Msg = RequestMessages;
+impl EventNode for GrowOnlyNode {
+    type Msg<'a> = RequestMessages;
+
+    fn new(Init::Init { node_id, node_ids }: Init, output: Output) -> Self {
+        let peers = node_ids
+            .into_iter()
+            .filter(|peer| *peer != node_id)
+            .collect();
+        Self {
+            node_id,
+            peers,
+            msg_seq_id: MsgId::ONE,
+            total: 0,
+            stored: Some(0),
+            committing: false,
+            read: HashMap::new(),
+            commit_interval: Duration::from_millis(50),
+            seq_kv: Kv::seq_kv(),
+            output,
+        }
+    }
 
-    fn new(init: dist_sys_challenge::Init) -> Self {
-        let (sender, receiver) = std::sync::mpsc::channel();
+    fn start(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        ctx.set_timer(
+            Timer::every("commit", self.commit_interval).with_jitter(self.commit_interval / 5),
+        );
+        Ok(())
+    }
 
-        let sender_clone = sender.clone();
-        std::thread::spawn(|| processor(init, receiver));
-        std::thread::spawn(move || -> std::io::Result<()> {
-            loop {
-                std::thread::sleep(Duration::from_millis(50));
-                sender_clone.send(Action::Commit).map_err(
-                    |err: std::sync::mpsc::SendError<Action>| {
-                        std::io::Error::new(std::io::ErrorKind::BrokenPipe, err)
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &dist_sys_challenge::Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        match request.payload() {
+            RequestMessages::Add { delta } => {
+                self.total += delta;
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::AddOk {},
+                )?;
+            }
+            RequestMessages::Read {} => {
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::ReadOk {
+                        value: self.total + self.read.values().sum::<usize>(),
                     },
                 )?;
             }
-        });
+        }
+        Ok(())
+    }
 
-        Self { processor: sender }
+    fn on_tick(&mut self, ctx: &mut Context<Self>, _timer: &str) -> dist_sys_challenge::Result<()> {
+        for peer in self.peers.clone() {
+            self.read(ctx, peer)?;
+        }
+        self.commit(ctx)
     }
+}
 
-    fn process(&mut self, request: &dist_sys_challenge::Message<Self::Msg>) -> std::io::Result<()> {
-        self.processor
-            .send(Action::Msg(request.clone()))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))?;
-        Ok(())
+// Every node counts its own adds in a g-counter that is gossiped to the others,
+// adds never wait for anyone and reads converge once gossip gets through again
+struct Counter;
+
+impl Operations for Counter {
+    type State = GCounter;
+    type Request = RequestMessages;
+    type Reply = ResponseMessages;
+
+    fn apply(counter: &mut GCounter, node: &NodeId, request: &RequestMessages) -> ResponseMessages {
+        match request {
+            RequestMessages::Add { delta } => {
+                counter.increment(node, *delta as u64);
+                ResponseMessages::AddOk {}
+            }
+            RequestMessages::Read {} => ResponseMessages::ReadOk {
+                value: counter.value() as usize,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dist_sys_challenge::sim::{
+        services::{LinKv, SeqKv},
+        Network,
+    };
+    use serde_json::json;
+
+    fn converges(net: &mut Network) {
+        let nodes = net.spawn::<GrowOnlyNode>(3);
+        for (i, node) in nodes.iter().enumerate() {
+            for delta in 1..=10 {
+                let reply = net.call(node, json!({"type": "add", "delta": delta * (i + 1)}));
+                assert_eq!(reply.payload()["type"], "add_ok");
+            }
+        }
+        net.run_for(Duration::from_secs(1));
+
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "read"}));
+            assert_eq!(reply.payload()["value"], 55 * 6);
+        }
+    }
+
+    #[test]
+    fn simulated() {
+        let mut net = Network::new(0);
+        net.add_service::<SeqKv>("seq-kv");
+        converges(&mut net);
+    }
+
+    #[test]
+    fn linearizable_kv() {
+        let mut net = Network::new(0);
+        net.add_service::<LinKv>("seq-kv");
+        converges(&mut net);
+    }
+
+    #[test]
+    fn seq_kv_unavailable() {
+        let mut net = Network::new(0);
+        let nodes = net.spawn::<GrowOnlyNode>(2);
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "add", "delta": 5}));
+            assert_eq!(reply.payload()["type"], "add_ok");
+        }
+        // every read times out, the deltas have to survive until seq-kv shows up
+        net.run_for(Duration::from_secs(3));
+        net.add_service::<SeqKv>("seq-kv");
+        net.run_for(Duration::from_secs(1));
+
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "read"}));
+            assert_eq!(reply.payload()["value"], 10);
+        }
+    }
+
+    #[test]
+    fn lost_cas_replies() {
+        let mut net = Network::new(0);
+        net.add_service::<SeqKv>("seq-kv");
+        let nodes = net.spawn::<GrowOnlyNode>(2);
+        // the cas goes through but the node only sees a timeout, retrying must not count
+        // the adds twice
+        net.drop_if(|msg| msg.payload()["type"] == "cas_ok");
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "add", "delta": 5}));
+            assert_eq!(reply.payload()["type"], "add_ok");
+        }
+        net.run_for(Duration::from_secs(3));
+        let reply = net.call(&nodes[0], json!({"type": "add", "delta": 1}));
+        assert_eq!(reply.payload()["type"], "add_ok");
+        net.heal();
+        net.run_for(Duration::from_secs(1));
+
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "read"}));
+            assert_eq!(reply.payload()["value"], 11);
+        }
+    }
+
+    #[test]
+    fn crdt() {
+        let mut net = Network::new(0);
+        let nodes = net.spawn::<GossipNode<Counter>>(3);
+        for (i, node) in nodes.iter().enumerate() {
+            let reply = net.call(node, json!({"type": "add", "delta": i + 1}));
+            assert_eq!(reply.payload()["type"], "add_ok");
+        }
+
+        // adds are answered right away during a partition, the other side only sees them
+        // once it heals
+        net.partition(&[&nodes[..1], &nodes[1..]]);
+        let reply = net.call(&nodes[0], json!({"type": "add", "delta": 10}));
+        assert_eq!(reply.payload()["type"], "add_ok");
+        net.run_for(Duration::from_secs(1));
+        let reply = net.call(&nodes[1], json!({"type": "read"}));
+        assert!(reply.payload()["value"].as_u64().unwrap() < 16);
+
+        net.heal();
+        net.run_for(Duration::from_secs(1));
+        for node in &nodes {
+            let reply = net.call(node, json!({"type": "read"}));
+            assert_eq!(reply.payload()["value"], 16);
+        }
+
+        // once everyone has everything gossip stops
+        let sent = net.messages_sent();
+        net.run_for(Duration::from_secs(5));
+        assert_eq!(net.messages_sent(), sent);
     }
 }
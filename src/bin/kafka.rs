@@ -0,0 +1,525 @@
+use std::collections::{BTreeMap, HashMap};
+
+use dist_sys_challenge::{
+    event_loop::{Context, Event},
+    kv::{Kv, KvError},
+    EventNode, Init, Message, MsgId, NodeId, Output, Payload,
+};
+use serde::{Deserialize, Serialize};
+
+fn main() -> dist_sys_challenge::Result<()> {
+    dist_sys_challenge::event_loop::run::<KafkaNode>()
+}
+
+// Every log lives in lin-kv, the message at an offset is `msg/{key}/{offset}`. A send claims
+// the first free offset by creating its entry there with a cas, so an offset is never taken
+// without its message and polls, which stop at the first missing entry, can't be held up by
+// a send whose cas timed out or whose node crashed. `offset/{key}` is only a hint where to
+// start looking, bumped after each claim. Nodes cache what they have written or read,
+// so polls only go to lin-kv for new messages.
+struct KafkaNode {
+    node_id: NodeId,
+    msg_seq_id: MsgId,
+    lin_kv: Kv<String, usize>,
+    entries: Kv<String, Entry>,
+    next_entry: usize,
+    logs: HashMap<String, BTreeMap<usize, usize>>,
+    pending: HashMap<usize, Pending>,
+    next_pending: usize,
+    output: Output,
+}
+
+const POLL_LIMIT: usize = 16;
+
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
+enum RequestMessages {
+    Send { key: String, msg: usize },
+    Poll { offsets: HashMap<String, usize> },
+    CommitOffsets { offsets: HashMap<String, usize> },
+    ListCommittedOffsets { keys: Vec<String> },
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Payload)]
+enum ResponseMessages {
+    SendOk {
+        offset: usize,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    CommitOffsetsOk {},
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+}
+
+// Tagged with the send it belongs to, the claim of another send with the same `msg`
+// would succeed on it otherwise.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    msg: usize,
+    node: NodeId,
+    send: usize,
+}
+
+// Client requests that wait for more than one lin-kv operation
+enum Pending {
+    Poll {
+        request: Message<RequestMessages>,
+        remaining: usize,
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    Commit {
+        request: Message<RequestMessages>,
+        remaining: usize,
+    },
+    List {
+        request: Message<RequestMessages>,
+        remaining: usize,
+        offsets: HashMap<String, usize>,
+    },
+}
+
+fn offset_key(key: &str) -> String {
+    format!("offset/{key}")
+}
+
+fn msg_key(key: &str, offset: usize) -> String {
+    format!("msg/{key}/{offset}")
+}
+
+fn commit_key(key: &str) -> String {
+    format!("commit/{key}")
+}
+
+impl KafkaNode {
+    fn allocate(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: Message<RequestMessages>,
+        key: String,
+        msg: usize,
+    ) -> dist_sys_challenge::Result<()> {
+        let entry = Entry {
+            msg,
+            node: self.node_id.clone(),
+            send: self.next_entry,
+        };
+        self.next_entry += 1;
+        self.lin_kv.read(
+            ctx,
+            &mut self.msg_seq_id,
+            offset_key(&key),
+            move |node: &mut Self, ctx, result| {
+                let hint = match result {
+                    Ok(hint) => hint,
+                    Err(KvError::KeyDoesNotExist) => 0,
+                    Err(err) => {
+                        request.respond_error(&mut node.output, err.into(), None)?;
+                        return Ok(());
+                    }
+                };
+                let known = node
+                    .logs
+                    .get(&key)
+                    .and_then(|log| log.last_key_value())
+                    .map_or(0, |(offset, _)| offset + 1);
+                node.claim(ctx, request, key, entry, hint, hint.max(known))
+            },
+        )?;
+        Ok(())
+    }
+
+    // Create `entry` at `offset` or, if that is taken, at the next free one after it
+    fn claim(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: Message<RequestMessages>,
+        key: String,
+        entry: Entry,
+        hint: usize,
+        offset: usize,
+    ) -> dist_sys_challenge::Result<()> {
+        self.entries.cas(
+            ctx,
+            &mut self.msg_seq_id,
+            msg_key(&key, offset),
+            entry.clone(),
+            entry.clone(),
+            true,
+            move |node: &mut Self, ctx, result| match result {
+                Ok(()) => {
+                    node.logs
+                        .entry(key.clone())
+                        .or_default()
+                        .insert(offset, entry.msg);
+                    // a lost race only leaves the hint behind, the next send skips ahead
+                    node.lin_kv.cas(
+                        ctx,
+                        &mut node.msg_seq_id,
+                        offset_key(&key),
+                        hint,
+                        offset + 1,
+                        true,
+                        |_: &mut Self, _, _| Ok(()),
+                    )?;
+                    request.respond(
+                        &mut node.output,
+                        Some(&mut node.msg_seq_id),
+                        ResponseMessages::SendOk { offset },
+                    )?;
+                    Ok(())
+                }
+                Err(KvError::PreConditionFailed) => {
+                    node.claim(ctx, request, key, entry, hint, offset + 1)
+                }
+                // after a timeout the entry may be in the log anyway, then it is a message
+                // like any other but the client can't know its offset
+                Err(err) => Ok(request.respond_error(&mut node.output, err.into(), None)?),
+            },
+        )?;
+        Ok(())
+    }
+
+    // Raise the committed offset of `key` to `offset`, it never goes down
+    fn commit(
+        &mut self,
+        ctx: &mut Context<Self>,
+        commit: usize,
+        key: String,
+        offset: usize,
+    ) -> dist_sys_challenge::Result<()> {
+        self.lin_kv.read(
+            ctx,
+            &mut self.msg_seq_id,
+            commit_key(&key),
+            move |node: &mut Self, ctx, result| {
+                // a missing key is created with `offset`, `from` only matters if it exists
+                let committed = match result {
+                    Ok(committed) if committed >= offset => return node.finish(commit),
+                    Ok(committed) => committed,
+                    Err(KvError::KeyDoesNotExist) => offset,
+                    Err(err) => return node.fail(commit, err),
+                };
+                node.lin_kv.cas(
+                    ctx,
+                    &mut node.msg_seq_id,
+                    commit_key(&key),
+                    committed,
+                    offset,
+                    true,
+                    move |node: &mut Self, ctx, result| match result {
+                        Ok(()) => node.finish(commit),
+                        // another commit came in between
+                        Err(KvError::PreConditionFailed) => node.commit(ctx, commit, key, offset),
+                        Err(err) => node.fail(commit, err),
+                    },
+                )?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    fn poll_key(
+        &mut self,
+        ctx: &mut Context<Self>,
+        poll: usize,
+        key: String,
+        mut offset: usize,
+    ) -> dist_sys_challenge::Result<()> {
+        loop {
+            let Some(Pending::Poll { msgs, .. }) = self.pending.get_mut(&poll) else {
+                return Ok(());
+            };
+            let polled = msgs.entry(key.clone()).or_default();
+            if polled.len() >= POLL_LIMIT {
+                return self.finish(poll);
+            }
+            match self.logs.get(&key).and_then(|log| log.get(&offset)) {
+                Some(&msg) => {
+                    polled.push((offset, msg));
+                    offset += 1;
+                }
+                None => break,
+            }
+        }
+
+        self.entries.read(
+            ctx,
+            &mut self.msg_seq_id,
+            msg_key(&key, offset),
+            move |node: &mut Self, ctx, result| match result {
+                Ok(entry) => {
+                    node.logs
+                        .entry(key.clone())
+                        .or_default()
+                        .insert(offset, entry.msg);
+                    node.poll_key(ctx, poll, key, offset)
+                }
+                // the end of the log, offsets are only taken together with their message
+                Err(_) => node.finish(poll),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self, pending: usize) -> dist_sys_challenge::Result<()> {
+        let Some(
+            Pending::Poll { remaining, .. }
+            | Pending::Commit { remaining, .. }
+            | Pending::List { remaining, .. },
+        ) = self.pending.get_mut(&pending)
+        else {
+            return Ok(());
+        };
+        *remaining -= 1;
+        if *remaining > 0 {
+            return Ok(());
+        }
+
+        let (request, reply) = match self
+            .pending
+            .remove(&pending)
+            .expect("pending request exists")
+        {
+            Pending::Poll { request, msgs, .. } => (
+                request,
+                ResponseMessages::PollOk {
+                    msgs: msgs
+                        .into_iter()
+                        .filter(|(_, msgs)| !msgs.is_empty())
+                        .collect(),
+                },
+            ),
+            Pending::Commit { request, .. } => (request, ResponseMessages::CommitOffsetsOk {}),
+            Pending::List {
+                request, offsets, ..
+            } => (
+                request,
+                ResponseMessages::ListCommittedOffsetsOk { offsets },
+            ),
+        };
+        request.respond(&mut self.output, Some(&mut self.msg_seq_id), reply)?;
+        Ok(())
+    }
+
+    fn fail(&mut self, pending: usize, err: KvError) -> dist_sys_challenge::Result<()> {
+        match self.pending.remove(&pending) {
+            Some(
+                Pending::Poll { request, .. }
+                | Pending::Commit { request, .. }
+                | Pending::List { request, .. },
+            ) => request.respond_error(&mut self.output, err.into(), None)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn track(&mut self, pending: Pending) -> usize {
+        let id = self.next_pending;
+        self.next_pending += 1;
+        self.pending.insert(id, pending);
+        id
+    }
+
+    fn handle(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<RequestMessages>,
+    ) -> dist_sys_challenge::Result<()> {
+        match request.payload() {
+            RequestMessages::Send { key, msg } => {
+                self.allocate(ctx, request.clone(), key.clone(), *msg)?;
+            }
+            RequestMessages::Poll { offsets } => {
+                let poll = self.track(Pending::Poll {
+                    request: request.clone(),
+                    remaining: offsets.len() + 1,
+                    msgs: HashMap::new(),
+                });
+                for (key, offset) in offsets {
+                    self.poll_key(ctx, poll, key.clone(), *offset)?;
+                }
+                self.finish(poll)?;
+            }
+            RequestMessages::CommitOffsets { offsets } => {
+                let commit = self.track(Pending::Commit {
+                    request: request.clone(),
+                    remaining: offsets.len() + 1,
+                });
+                for (key, offset) in offsets {
+                    self.commit(ctx, commit, key.clone(), *offset)?;
+                }
+                self.finish(commit)?;
+            }
+            RequestMessages::ListCommittedOffsets { keys } => {
+                let list = self.track(Pending::List {
+                    request: request.clone(),
+                    remaining: keys.len() + 1,
+                    offsets: HashMap::new(),
+                });
+                for key in keys {
+                    let key = key.clone();
+                    self.lin_kv.read(
+                        ctx,
+                        &mut self.msg_seq_id,
+                        commit_key(&key),
+                        move |node: &mut Self, _ctx, result| match result {
+                            Ok(offset) => {
+                                if let Some(Pending::List { offsets, .. }) =
+                                    node.pending.get_mut(&list)
+                                {
+                                    offsets.insert(key, offset);
+                                }
+                                node.finish(list)
+                            }
+                            Err(KvError::KeyDoesNotExist) => node.finish(list),
+                            Err(err) => node.fail(list, err),
+                        },
+                    )?;
+                }
+                self.finish(list)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventNode for KafkaNode {
+    type Msg<'a> = RequestMessages;
+
+    fn new(Init::Init { node_id, .. }: Init, output: Output) -> Self {
+        Self {
+            node_id,
+            msg_seq_id: MsgId::ONE,
+            lin_kv: Kv::lin_kv(),
+            entries: Kv::lin_kv(),
+            next_entry: 0,
+            logs: HashMap::new(),
+            pending: HashMap::new(),
+            next_pending: 0,
+            output,
+        }
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        self.handle(ctx, request)
+    }
+
+    fn on_event(
+        &mut self,
+        ctx: &mut Context<Self>,
+        event: &Event<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        match event {
+            Event::Message(request) => self.handle(ctx, request),
+            Event::Reply(_) | Event::Timer(_) => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::{services::LinKv, Network};
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    net.add_service::<LinKv>("lin-kv");
+    let nodes = net.spawn::<KafkaNode>(2);
+
+    let mut offsets = Vec::new();
+    for msg in 0..6 {
+        let reply = net.call(
+            &nodes[msg % nodes.len()],
+            json!({"type": "send", "key": "k1", "msg": msg * 10}),
+        );
+        assert_eq!(reply.payload()["type"], "send_ok");
+        offsets.push(reply.payload()["offset"].as_u64().unwrap());
+    }
+    assert_eq!(offsets, [0, 1, 2, 3, 4, 5]);
+
+    for node in &nodes {
+        let reply = net.call(node, json!({"type": "poll", "offsets": {"k1": 2, "k2": 0}}));
+        assert_eq!(
+            reply.payload()["msgs"],
+            json!({"k1": [[2, 20], [3, 30], [4, 40], [5, 50]]})
+        );
+    }
+
+    let reply = net.call(
+        &nodes[0],
+        json!({"type": "commit_offsets", "offsets": {"k1": 3}}),
+    );
+    assert_eq!(reply.payload()["type"], "commit_offsets_ok");
+    let reply = net.call(
+        &nodes[1],
+        json!({"type": "list_committed_offsets", "keys": ["k1", "k2"]}),
+    );
+    assert_eq!(reply.payload()["offsets"], json!({"k1": 3}));
+
+    // a late commit doesn't move the offset back
+    let reply = net.call(
+        &nodes[1],
+        json!({"type": "commit_offsets", "offsets": {"k1": 1}}),
+    );
+    assert_eq!(reply.payload()["type"], "commit_offsets_ok");
+    let reply = net.call(
+        &nodes[0],
+        json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+    );
+    assert_eq!(reply.payload()["offsets"], json!({"k1": 3}));
+}
+
+#[test]
+fn lin_kv_unavailable() {
+    use dist_sys_challenge::{sim::Network, ErrorCode};
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<KafkaNode>(1);
+    let reply = net.call(&nodes[0], json!({"type": "send", "key": "k1", "msg": 1}));
+    assert_eq!(reply.payload()["type"], "error");
+    let code = serde_json::from_value::<ErrorCode>(reply.payload()["code"].clone()).unwrap();
+    assert_eq!(code, ErrorCode::Timeout);
+    // the offset may have been allocated anyway
+    assert!(!code.is_definite());
+}
+
+#[test]
+fn lost_cas_reply() {
+    use dist_sys_challenge::{
+        sim::{services::LinKv, Network},
+        ErrorCode,
+    };
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    net.add_service::<LinKv>("lin-kv");
+    let nodes = net.spawn::<KafkaNode>(2);
+
+    // offset 0 is taken but the node never learns it
+    let mut dropped = false;
+    net.drop_if(move |msg| {
+        let first = msg.payload()["type"] == "cas_ok" && !dropped;
+        dropped |= first;
+        first
+    });
+    let reply = net.call(&nodes[0], json!({"type": "send", "key": "k1", "msg": 10}));
+    let code = serde_json::from_value::<ErrorCode>(reply.payload()["code"].clone()).unwrap();
+    assert_eq!(code, ErrorCode::Timeout);
+    let reply = net.call(&nodes[1], json!({"type": "send", "key": "k1", "msg": 11}));
+    assert_eq!(reply.payload()["offset"], 1);
+
+    // the message of the timed out send doesn't hide the acknowledged one
+    for node in &nodes {
+        let reply = net.call(node, json!({"type": "poll", "offsets": {"k1": 0}}));
+        assert_eq!(reply.payload()["msgs"], json!({"k1": [[0, 10], [1, 11]]}));
+    }
+}
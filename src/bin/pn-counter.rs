@@ -0,0 +1,76 @@
+use dist_sys_challenge::{
+    crdt::PnCounter,
+    gossip::{GossipNode, Operations},
+    NodeId, Payload,
+};
+
+fn main() -> dist_sys_challenge::Result<()> {
+    dist_sys_challenge::event_loop::run::<GossipNode<Counter>>()
+}
+
+// Same as the crdt mode of grow-only, with a counter that also counts decrements
+struct Counter;
+
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
+enum RequestMessages {
+    Add { delta: i64 },
+    Read {},
+}
+
+#[derive(Debug, Payload)]
+enum ResponseMessages {
+    AddOk {},
+    ReadOk { value: i64 },
+}
+
+impl Operations for Counter {
+    type State = PnCounter;
+    type Request = RequestMessages;
+    type Reply = ResponseMessages;
+
+    fn apply(
+        counter: &mut PnCounter,
+        node: &NodeId,
+        request: &RequestMessages,
+    ) -> ResponseMessages {
+        match request {
+            RequestMessages::Add { delta } => {
+                counter.add(node, *delta);
+                ResponseMessages::AddOk {}
+            }
+            RequestMessages::Read {} => ResponseMessages::ReadOk {
+                value: counter.value(),
+            },
+        }
+    }
+}
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+    use std::time::Duration;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<GossipNode<Counter>>(3);
+    for (i, node) in nodes.iter().enumerate() {
+        for delta in [5, -3, -(i as i64)] {
+            let reply = net.call(node, json!({"type": "add", "delta": delta}));
+            assert_eq!(reply.payload()["type"], "add_ok");
+        }
+    }
+
+    // a partitioned node keeps taking adds, the others see them once it heals
+    net.partition(&[&nodes[..1], &nodes[1..]]);
+    let reply = net.call(&nodes[0], json!({"type": "add", "delta": -10}));
+    assert_eq!(reply.payload()["type"], "add_ok");
+    net.run_for(Duration::from_secs(1));
+    net.heal();
+    net.run_for(Duration::from_secs(1));
+
+    for node in &nodes {
+        let reply = net.call(node, json!({"type": "read"}));
+        assert_eq!(reply.payload()["value"], 3 * 2 - 3 - 10);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{collections::HashMap, time::Duration};
+
+use dist_sys_challenge::{
+    clock::Lamport,
+    event_loop::{Context, Timer},
+    EventNode, Init, Message, MsgId, NodeId, Output, Payload,
+};
+use serde::{Deserialize, Serialize};
+
+fn main() -> dist_sys_challenge::Result<()> {
+    dist_sys_challenge::event_loop::run::<TxnNode>()
+}
+
+// Transactions are applied locally and answered right away, their writes are then
+// replicated to every other node as a whole. All writes of a transaction carry the same
+// lamport stamp and replicas keep the write with the highest stamp per key, so a read
+// never sees part of a transaction or a value that was overwritten within it.
+struct TxnNode {
+    node_id: NodeId,
+    peers: Vec<NodeId>,
+    msg_seq_id: MsgId,
+    clock: Lamport,
+    store: HashMap<usize, (Stamp, usize)>,
+    // writes of our own transactions in the order they were committed
+    log: Vec<Replicated>,
+    // how much of `log` each peer has confirmed
+    acked: HashMap<NodeId, usize>,
+    replicate_interval: Duration,
+    output: Output,
+}
+
+type Stamp = (Lamport, NodeId);
+
+// most transactions sent to a peer at once, the rest follows once these are confirmed
+const REPLICATE_BATCH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OpKind {
+    R,
+    W,
+}
+
+type Op = (OpKind, usize, Option<usize>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Replicated {
+    stamp: Stamp,
+    writes: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
+enum RequestMessages {
+    Txn { txn: Vec<Op> },
+    Replicate { from: usize, txns: Vec<Replicated> },
+    ReplicateOk { upto: usize },
+}
+
+#[derive(Debug, Payload)]
+enum ResponseMessages {
+    TxnOk { txn: Vec<Op> },
+    Replicate { from: usize, txns: Vec<Replicated> },
+    ReplicateOk { upto: usize },
+}
+
+impl TxnNode {
+    fn apply(&mut self, stamp: &Stamp, writes: &[(usize, usize)]) {
+        self.clock.merge(stamp.0);
+        for &(key, value) in writes {
+            match self.store.get(&key) {
+                Some((newest, _)) if newest >= stamp => {}
+                _ => {
+                    self.store.insert(key, (stamp.clone(), value));
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, txn: &[Op]) -> Vec<Op> {
+        let stamp = (self.clock.tick(), self.node_id.clone());
+
+        // reads within the transaction see its own earlier writes
+        let mut written = HashMap::new();
+        let result = txn
+            .iter()
+            .map(|&(kind, key, value)| match kind {
+                OpKind::R => {
+                    let read = written
+                        .get(&key)
+                        .copied()
+                        .or_else(|| self.store.get(&key).map(|(_, value)| *value));
+                    (kind, key, read)
+                }
+                OpKind::W => {
+                    if let Some(value) = value {
+                        written.insert(key, value);
+                    }
+                    (kind, key, value)
+                }
+            })
+            .collect();
+
+        if !written.is_empty() {
+            let writes = written.into_iter().collect::<Vec<_>>();
+            self.apply(&stamp, &writes);
+            self.log.push(Replicated { stamp, writes });
+        }
+        result
+    }
+}
+
+impl EventNode for TxnNode {
+    type Msg<'a> = RequestMessages;
+
+    fn new(Init::Init { node_id, node_ids }: Init, output: Output) -> Self {
+        let peers = node_ids
+            .into_iter()
+            .filter(|peer| *peer != node_id)
+            .collect();
+        Self {
+            node_id,
+            peers,
+            msg_seq_id: MsgId::ONE,
+            clock: Lamport::default(),
+            store: HashMap::new(),
+            log: Vec::new(),
+            acked: HashMap::new(),
+            replicate_interval: Duration::from_millis(100),
+            output,
+        }
+    }
+
+    fn start(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        ctx.set_timer(
+            Timer::every("replicate", self.replicate_interval)
+                .with_jitter(self.replicate_interval / 5),
+        );
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        match request.payload() {
+            RequestMessages::Txn { txn } => {
+                let txn = self.execute(txn);
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::TxnOk { txn },
+                )?;
+            }
+            RequestMessages::Replicate { from, txns } => {
+                for txn in txns {
+                    self.apply(&txn.stamp, &txn.writes);
+                }
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::ReplicateOk {
+                        upto: from + txns.len(),
+                    },
+                )?;
+            }
+            RequestMessages::ReplicateOk { upto } => {
+                let acked = self.acked.entry(request.src().clone()).or_default();
+                *acked = (*acked).max(*upto);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_tick(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        _timer: &str,
+    ) -> dist_sys_challenge::Result<()> {
+        // what a peer hasn't confirmed is resent, so lost messages and partitions
+        // only delay replication
+        for peer in &self.peers {
+            let from = self.acked.get(peer).copied().unwrap_or(0);
+            if from < self.log.len() {
+                let upto = self.log.len().min(from + REPLICATE_BATCH);
+                Message::new(
+                    self.node_id.clone(),
+                    peer.clone(),
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::Replicate {
+                        from,
+                        txns: self.log[from..upto].to_vec(),
+                    },
+                )
+                .send(&mut self.output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<TxnNode>(3);
+
+    let reply = net.call(
+        &nodes[0],
+        json!({"type": "txn", "txn": [["w", 1, 10], ["r", 1, null], ["r", 2, null], ["w", 2, 20]]}),
+    );
+    assert_eq!(
+        reply.payload()["txn"],
+        json!([["w", 1, 10], ["r", 1, 10], ["r", 2, null], ["w", 2, 20]])
+    );
+    let reply = net.call(&nodes[1], json!({"type": "txn", "txn": [["w", 1, 11]]}));
+    assert_eq!(reply.payload()["type"], "txn_ok");
+    net.run_for(Duration::from_secs(1));
+
+    // the later write wins everywhere, the rest of the first transaction survives
+    for node in &nodes {
+        let reply = net.call(
+            node,
+            json!({"type": "txn", "txn": [["r", 1, null], ["r", 2, null]]}),
+        );
+        assert_eq!(reply.payload()["txn"], json!([["r", 1, 11], ["r", 2, 20]]));
+    }
+}
+
+#[test]
+fn partitioned() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<TxnNode>(3);
+    net.partition(&[&nodes[..1], &nodes[1..]]);
+
+    // both sides write key 1, more than one batch of transactions piles up on n0
+    for key in 0..2 * REPLICATE_BATCH {
+        net.call(&nodes[0], json!({"type": "txn", "txn": [["w", key, 1]]}));
+    }
+    net.call(
+        &nodes[1],
+        json!({"type": "txn", "txn": [["w", 1, 2], ["w", 100, 2]]}),
+    );
+    net.call(&nodes[2], json!({"type": "txn", "txn": [["w", 1, 3]]}));
+    net.run_for(Duration::from_secs(1));
+    let reply = net.call(&nodes[0], json!({"type": "txn", "txn": [["r", 100, null]]}));
+    assert_eq!(reply.payload()["txn"], json!([["r", 100, null]]));
+
+    net.heal();
+    net.run_for(Duration::from_secs(1));
+    let read = json!({"type": "txn", "txn": [["r", 1, null], ["r", 63, null], ["r", 100, null]]});
+    let replies = nodes
+        .iter()
+        .map(|node| net.call(node, read.clone()).payload()["txn"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(replies[0][1], json!(["r", 63, 1]));
+    assert_eq!(replies[0][2], json!(["r", 100, 2]));
+    assert!(
+        replies.iter().all(|reply| *reply == replies[0]),
+        "{replies:?}"
+    );
+}
@@ -1,59 +1,401 @@
-use std::io::stdout;
+use std::{collections::VecDeque, ops::Range, time::Duration};
 
-use dist_sys_challenge::{Init, MsgId, Node, NodeId, Payload};
+use dist_sys_challenge::{
+    event_loop::{Context, Timer},
+    ids::{IdGenerator, Strategy},
+    kv::{Kv, KvError},
+    ErrorCode, EventNode, Init, Message, MsgId, NodeError, Output, Payload,
+};
 use serde::{Deserialize, Serialize};
 
-fn main() -> std::io::Result<()> {
-    dist_sys_challenge::run::<UniqueIdsNode>()
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // e.g. `UNIQUE_IDS_STRATEGY=ulid`, `lease` leases the ids from lin-kv,
+    // see `Strategy::from_str` for the others
+    match std::env::var("UNIQUE_IDS_STRATEGY").ok().as_deref() {
+        Some("lease") => dist_sys_challenge::event_loop::run::<LeasedIdsNode>()?,
+        strategy => {
+            let strategy = strategy.map_or(Ok(Strategy::Snowflake), str::parse)?;
+            dist_sys_challenge::event_loop::run_with(|init, output| {
+                LocalIdsNode::with_strategy(init, output, &strategy)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// Generates the ids on its own, see `Strategy` for how they are kept apart
+struct LocalIdsNode {
+    msg_seq_id: MsgId,
+    ids: Box<dyn IdGenerator>,
+    output: Output,
 }
 
-struct UniqueIdsNode {
+// Hands out blocks of ids leased from a counter in lin-kv that holds the first id nobody
+// leased yet, a node advances it by a block with cas and hands the block out from memory.
+struct LeasedIdsNode {
     msg_seq_id: MsgId,
-    node_id: NodeId,
+    lin_kv: Kv<&'static str, u64>,
+    current: Range<u64>,
+    // leased before `current` runs out, so ids keep coming while lin-kv can't be reached
+    next: Option<Range<u64>>,
+    leasing: bool,
+    // the value of the counter as far as we know, a failed cas reads it again
+    counter: u64,
+    // requests that arrived while we were out of ids, at most `MAX_WAITING`
+    waiting: VecDeque<Message<RequestMessages>>,
+    output: Output,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+const COUNTER: &str = "unique-ids";
+const BLOCK: u64 = 100;
+const RETRY: Duration = Duration::from_millis(100);
+const MAX_WAITING: usize = 1000;
+
+#[derive(Debug, Clone, Payload)]
+#[payload(reply = ResponseMessages)]
 enum RequestMessages {
     Generate {},
 }
 
-impl Payload for RequestMessages {}
+// leased ids are numbers, the generators of `ids` produce strings
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u64),
+    Text(String),
+}
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[derive(Debug, Payload)]
 enum ResponseMessages {
-    GenerateOk { id: String },
+    GenerateOk { id: Id },
 }
 
-impl Payload for ResponseMessages {}
-
-impl Node for UniqueIdsNode {
-    type Msg = RequestMessages;
-
-    fn new(
-        Init::Init {
-            node_id,
-            node_ids: _,
-        }: dist_sys_challenge::Init,
+impl LocalIdsNode {
+    fn with_strategy(
+        Init::Init { node_id, node_ids }: Init,
+        output: Output,
+        strategy: &Strategy,
     ) -> Self {
+        let ids = strategy
+            .generator(&node_id, &node_ids)
+            .unwrap_or_else(|err| panic!("can't set up {strategy:?} ids: {err}"));
         Self {
             msg_seq_id: MsgId::ONE,
-            node_id,
+            ids,
+            output,
         }
     }
+}
+
+impl EventNode for LocalIdsNode {
+    type Msg<'a> = RequestMessages;
 
-    fn process(&mut self, request: &dist_sys_challenge::Message<Self::Msg>) -> std::io::Result<()> {
+    fn new(init: Init, output: Output) -> Self {
+        Self::with_strategy(init, output, &Strategy::Snowflake)
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
         match request.payload() {
             RequestMessages::Generate {} => {
-                let id = format!("{}@{}", self.msg_seq_id, self.node_id);
+                let id = self
+                    .ids
+                    .generate()
+                    .map_err(|err| NodeError::handler(ErrorCode::Crash, err.to_string()))?;
                 request.respond(
-                    &mut stdout(),
+                    &mut self.output,
                     Some(&mut self.msg_seq_id),
-                    ResponseMessages::GenerateOk { id },
+                    ResponseMessages::GenerateOk { id: Id::Text(id) },
                 )?;
             }
         }
         Ok(())
     }
 }
+
+impl LeasedIdsNode {
+    fn take(&mut self) -> Option<u64> {
+        if self.current.is_empty() {
+            self.current = self.next.take()?;
+        }
+        self.current.next()
+    }
+
+    // Lease the next block once the current one is half used, one lease at a time
+    fn lease(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        if self.leasing || self.next.is_some() || self.current.end - self.current.start > BLOCK / 2
+        {
+            return Ok(());
+        }
+        self.leasing = true;
+        let (from, to) = (self.counter, self.counter + BLOCK);
+        self.lin_kv.cas(
+            ctx,
+            &mut self.msg_seq_id,
+            COUNTER,
+            from,
+            to,
+            from == 0,
+            move |node: &mut Self, ctx, result| {
+                node.leasing = false;
+                match result {
+                    Ok(()) => {
+                        node.counter = to;
+                        node.next = Some(from..to);
+                        node.hand_out(ctx)
+                    }
+                    // someone else leased the block, try the one after the current counter
+                    Err(KvError::PreConditionFailed) => node.read_counter(ctx),
+                    // if the cas went through anyway its block is skipped, the next cas
+                    // fails and reads the counter
+                    Err(err) => Self::retry(ctx, err),
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    fn read_counter(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        self.leasing = true;
+        self.lin_kv.read(
+            ctx,
+            &mut self.msg_seq_id,
+            COUNTER,
+            |node: &mut Self, ctx, result| {
+                node.leasing = false;
+                match result {
+                    Ok(counter) => node.counter = counter,
+                    Err(KvError::KeyDoesNotExist) => node.counter = 0,
+                    Err(err) => return Self::retry(ctx, err),
+                }
+                node.lease(ctx)
+            },
+        )?;
+        Ok(())
+    }
+
+    fn retry(ctx: &mut Context<Self>, err: KvError) -> dist_sys_challenge::Result<()> {
+        ctx.set_timer(Timer::once("lease", RETRY));
+        match err {
+            // lin-kv is unreachable for now, the ids leased ahead cover for it
+            KvError::Timeout => Ok(()),
+            err => Err(err.into()),
+        }
+    }
+
+    // Answer the requests that waited for a lease
+    fn hand_out(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        while !self.waiting.is_empty() {
+            let Some(id) = self.take() else {
+                break;
+            };
+            let request = self.waiting.pop_front().expect("checked above");
+            request.respond(
+                &mut self.output,
+                Some(&mut self.msg_seq_id),
+                ResponseMessages::GenerateOk { id: Id::Number(id) },
+            )?;
+        }
+        self.lease(ctx)
+    }
+}
+
+impl EventNode for LeasedIdsNode {
+    type Msg<'a> = RequestMessages;
+
+    fn new(_: Init, output: Output) -> Self {
+        Self {
+            msg_seq_id: MsgId::ONE,
+            lin_kv: Kv::lin_kv(),
+            current: 0..0,
+            next: None,
+            leasing: false,
+            counter: 0,
+            waiting: VecDeque::new(),
+            output,
+        }
+    }
+
+    fn start(&mut self, ctx: &mut Context<Self>) -> dist_sys_challenge::Result<()> {
+        self.lease(ctx)
+    }
+
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> dist_sys_challenge::Result<()> {
+        match request.payload() {
+            RequestMessages::Generate {} => match self.take() {
+                Some(id) => request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    ResponseMessages::GenerateOk { id: Id::Number(id) },
+                )?,
+                // the client may retry, lin-kv has been unreachable for a while
+                None if self.waiting.len() >= MAX_WAITING => {
+                    return Err(NodeError::handler(
+                        ErrorCode::TemporarilyUnavailable,
+                        "out of ids until lin-kv can be reached again",
+                    ));
+                }
+                None => self.waiting.push_back(request.clone()),
+            },
+        }
+        self.lease(ctx)
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context<Self>, _timer: &str) -> dist_sys_challenge::Result<()> {
+        self.lease(ctx)
+    }
+}
+
+#[test]
+fn simulated() {
+    use dist_sys_challenge::sim::Network;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    let mut net = Network::new(0);
+    let nodes = net.spawn::<LocalIdsNode>(3);
+    let requests = (0..300)
+        .map(|i| net.send(&nodes[i % nodes.len()], json!({"type": "generate"})))
+        .collect::<Vec<_>>();
+    net.run_until_idle();
+
+    let ids = requests
+        .into_iter()
+        .map(|msg_id| {
+            net.reply(msg_id)
+                .expect("every request should be answered")
+                .payload()["id"]
+                .clone()
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(ids.len(), 300);
+}
+
+#[test]
+fn strategies() {
+    use dist_sys_challenge::NodeId;
+
+    let node_ids = serde_json::from_str::<Vec<NodeId>>(r#"["n0", "n1", "n2"]"#).unwrap();
+    let dir = std::env::temp_dir().join(format!("unique-ids-{}", std::process::id()));
+    for strategy in [
+        "snowflake",
+        "uuid",
+        "ulid",
+        &format!("counter:{}", dir.display()),
+    ] {
+        let strategy = strategy.parse::<Strategy>().unwrap();
+        let mut ids = Vec::new();
+        for node_id in &node_ids {
+            let mut generator = strategy.generator(node_id, &node_ids).unwrap();
+            let generated = (0..5000)
+                .map(|_| generator.generate().unwrap())
+                .collect::<Vec<_>>();
+            assert!(
+                generated.windows(2).all(|pair| pair[0] < pair[1]),
+                "{strategy:?} ids should be sorted"
+            );
+            ids.extend(generated);
+        }
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count, "{strategy:?} ids should be unique");
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn counter_restart() {
+    use dist_sys_challenge::{ids::PersistentCounter, NodeId};
+
+    let node_id = serde_json::from_str::<NodeId>(r#""n0""#).unwrap();
+    let path = std::env::temp_dir().join(format!("unique-ids-restart-{}", std::process::id()));
+    let mut counter = PersistentCounter::open(&path, node_id.clone()).unwrap();
+    let before = (0..10)
+        .map(|_| counter.generate().unwrap())
+        .collect::<Vec<_>>();
+    drop(counter);
+
+    let mut counter = PersistentCounter::open(&path, node_id).unwrap();
+    let after = counter.generate().unwrap();
+    assert!(before.iter().all(|id| *id < after));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn leased() {
+    use dist_sys_challenge::sim::{services::LinKv, Network};
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let lin_kv = net.add_service::<LinKv>("lin-kv");
+    let nodes = net.spawn::<LeasedIdsNode>(3);
+
+    let generate = |net: &mut Network, node, count| {
+        let requests = (0..count)
+            .map(|_| net.send(node, json!({"type": "generate"})))
+            .collect::<Vec<_>>();
+        net.run_for(Duration::from_secs(1));
+        requests
+            .into_iter()
+            .map(|msg_id| {
+                net.reply(msg_id)
+                    .expect("every request should be answered")
+                    .payload()["id"]
+                    .as_u64()
+                    .expect("leased ids are numbers")
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut ids = Vec::new();
+    for node in &nodes {
+        ids.extend(generate(&mut net, node, 250));
+    }
+    // without lin-kv the block leased ahead still lasts a while
+    net.partition(&[&[nodes[0].clone()], &[lin_kv]]);
+    ids.extend(generate(&mut net, &nodes[0], BLOCK / 2));
+
+    let count = ids.len();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), count, "ids should be unique");
+    // every node leases at most two blocks it doesn't use up
+    assert!(*ids.last().unwrap() < count as u64 + nodes.len() as u64 * 2 * BLOCK);
+}
+
+#[test]
+fn waiting_is_bounded() {
+    use dist_sys_challenge::sim::{services::LinKv, Network};
+    use serde_json::json;
+
+    let mut net = Network::new(0);
+    let lin_kv = net.add_service::<LinKv>("lin-kv");
+    let nodes = net.spawn::<LeasedIdsNode>(1);
+    net.partition(&[&nodes, &[lin_kv]]);
+
+    let requests = (0..=MAX_WAITING)
+        .map(|_| net.send(&nodes[0], json!({"type": "generate"})))
+        .collect::<Vec<_>>();
+    net.run_for(Duration::from_secs(1));
+    let rejected = requests
+        .iter()
+        .filter_map(|&msg_id| net.reply(msg_id))
+        .map(|reply| reply.payload()["code"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(rejected, [json!(ErrorCode::TemporarilyUnavailable)]);
+
+    // the requests that waited are answered once lin-kv is back
+    net.heal();
+    net.run_for(Duration::from_secs(1));
+    assert!(requests[..MAX_WAITING]
+        .iter()
+        .all(|&msg_id| net.reply(msg_id).is_some()));
+}
@@ -0,0 +1,395 @@
+//! Logical clocks to order events across nodes.
+//!
+//! A node that keeps a clock sets it with [`Context::set_clock`], the runtime then stamps
+//! messages sent through the [`Context`] and merges the stamps of the messages it receives,
+//! so [`Context::clock`] is always ahead of everything the node has seen.
+//!
+//! [`Context`]: crate::event_loop::Context
+//! [`Context::set_clock`]: crate::event_loop::Context::set_clock
+//! [`Context::clock`]: crate::event_loop::Context::clock
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::NodeId;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Lamport(u64);
+
+impl Lamport {
+    pub fn time(&self) -> u64 {
+        self.0
+    }
+
+    /// Advance for a local event or a send, returns the new time.
+    pub fn tick(&mut self) -> Self {
+        self.0 += 1;
+        *self
+    }
+
+    /// Move past a received time, receiving counts as an event itself.
+    pub fn merge(&mut self, other: Self) -> Self {
+        self.0 = self.0.max(other.0) + 1;
+        *self
+    }
+}
+
+/// Counts the events of every node, ordered by happened-before, `partial_cmp` is `None`
+/// for concurrent clocks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<NodeId, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node: &NodeId) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Count an event of `node`.
+    pub fn tick(&mut self, node: &NodeId) {
+        *self.0.entry(node.clone()).or_default() += 1;
+    }
+
+    /// Take the events of `other` into account, without counting an event.
+    pub fn merge(&mut self, other: &Self) {
+        for (node, &count) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialEq for VectorClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for VectorClock {}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        // missing entries count as 0
+        self.0
+            .keys()
+            .chain(other.0.keys())
+            .map(|node| self.get(node).cmp(&other.get(node)))
+            .try_fold(Ordering::Equal, |order, next| match (order, next) {
+                (order, Ordering::Equal) => Some(order),
+                (Ordering::Equal, next) => Some(next),
+                (order, next) if order == next => Some(order),
+                _ => None,
+            })
+    }
+}
+
+/// Hybrid logical clock, a lamport clock that stays close to the wall clock,
+/// see <https://cse.buffalo.edu/tech-reports/2014-04.pdf>.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Hlc {
+    // milliseconds since the unix epoch
+    wall: u64,
+    logical: u32,
+}
+
+impl Hlc {
+    pub fn wall(&self) -> u64 {
+        self.wall
+    }
+
+    pub fn logical(&self) -> u32 {
+        self.logical
+    }
+
+    /// Milliseconds since the unix epoch, the `physical` time the methods below expect.
+    pub fn physical_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    /// Advance for a local event or a send, returns the new time.
+    pub fn tick(&mut self, physical: u64) -> Self {
+        if physical > self.wall {
+            self.wall = physical;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+        *self
+    }
+
+    /// Move past a received time, receiving counts as an event itself.
+    pub fn merge(&mut self, other: Self, physical: u64) -> Self {
+        let wall = self.wall.max(other.wall).max(physical);
+        self.logical = match (wall == self.wall, wall == other.wall) {
+            (true, true) => self.logical.max(other.logical) + 1,
+            (true, false) => self.logical + 1,
+            (false, true) => other.logical + 1,
+            (false, false) => 0,
+        };
+        self.wall = wall;
+        *self
+    }
+}
+
+/// The clock a node keeps, and the stamp in the `_clock` field of the messages it sends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Clock {
+    Lamport(Lamport),
+    Vector(VectorClock),
+    Hybrid(Hlc),
+}
+
+impl Clock {
+    /// Advance for a message `node` sends, returns the stamp for it.
+    pub fn tick(&mut self, node: &NodeId) -> Self {
+        match self {
+            Self::Lamport(clock) => {
+                clock.tick();
+            }
+            Self::Vector(clock) => clock.tick(node),
+            Self::Hybrid(clock) => {
+                clock.tick(Hlc::physical_now());
+            }
+        }
+        self.clone()
+    }
+
+    /// Merge the stamp of a message `node` received, stamps of another kind of clock are
+    /// ignored.
+    pub fn merge(&mut self, stamp: &Self, node: &NodeId) {
+        match (self, stamp) {
+            (Self::Lamport(clock), Self::Lamport(stamp)) => {
+                clock.merge(*stamp);
+            }
+            (Self::Vector(clock), Self::Vector(stamp)) => {
+                clock.merge(stamp);
+                clock.tick(node);
+            }
+            (Self::Hybrid(clock), Self::Hybrid(stamp)) => {
+                clock.merge(*stamp, Hlc::physical_now());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        event_loop::Context, sim::Network, EventNode, Init, Message, MsgId, Output, Payload, Result,
+    };
+
+    fn node(name: &str) -> NodeId {
+        NodeId(name.to_owned())
+    }
+
+    fn vector(counts: &[(&str, u64)]) -> VectorClock {
+        VectorClock(
+            counts
+                .iter()
+                .map(|&(name, count)| (node(name), count))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn lamport() {
+        let mut clock = Lamport::default();
+        assert_eq!(clock.tick().time(), 1);
+        assert_eq!(clock.merge(Lamport(5)).time(), 6);
+        // an older stamp still counts as an event
+        assert_eq!(clock.merge(Lamport(2)).time(), 7);
+    }
+
+    #[test]
+    fn vector_order() {
+        let a = vector(&[("n0", 1)]);
+        let b = vector(&[("n0", 1), ("n1", 1)]);
+        let c = vector(&[("n0", 2)]);
+        assert!(a < b && a < c);
+        assert_eq!(b.partial_cmp(&a), Some(Ordering::Greater));
+        assert!(b.concurrent(&c) && c.concurrent(&b));
+        assert!(!a.concurrent(&b));
+        // missing entries count as 0
+        assert_eq!(a, vector(&[("n0", 1), ("n1", 0)]));
+        assert_eq!(a.partial_cmp(&a), Some(Ordering::Equal));
+
+        let mut merged = b.clone();
+        merged.merge(&c);
+        assert_eq!(merged, vector(&[("n0", 2), ("n1", 1)]));
+        assert!(merged > b && merged > c);
+        merged.tick(&node("n2"));
+        assert_eq!(merged.get(&node("n2")), 1);
+    }
+
+    #[test]
+    fn hlc() {
+        let at = |wall, logical| Hlc { wall, logical };
+
+        // the wall clock moved on, the logical part starts over
+        assert_eq!(at(10, 3).tick(11), at(11, 0));
+        // it didn't, count within the millisecond
+        assert_eq!(at(10, 3).tick(9), at(10, 4));
+
+        // merge takes the largest wall time of the two clocks and the physical time
+        assert_eq!(at(10, 3).merge(at(10, 5), 9), at(10, 6));
+        assert_eq!(at(10, 3).merge(at(8, 5), 9), at(10, 4));
+        assert_eq!(at(8, 3).merge(at(10, 5), 9), at(10, 6));
+        assert_eq!(at(8, 3).merge(at(9, 5), 12), at(12, 0));
+    }
+
+    #[test]
+    fn clock_kinds() {
+        let n0 = node("n0");
+        let mut clock = Clock::Lamport(Lamport::default());
+        let stamp = clock.tick(&n0);
+        assert_eq!(stamp, Clock::Lamport(Lamport(1)));
+        clock.merge(&Clock::Lamport(Lamport(4)), &n0);
+        assert_eq!(clock, Clock::Lamport(Lamport(5)));
+        // stamps of another kind are ignored
+        clock.merge(&Clock::Vector(vector(&[("n1", 9)])), &n0);
+        assert_eq!(clock, Clock::Lamport(Lamport(5)));
+
+        let mut clock = Clock::Vector(VectorClock::default());
+        clock.merge(&Clock::Vector(vector(&[("n1", 2)])), &n0);
+        assert_eq!(clock, Clock::Vector(vector(&[("n0", 1), ("n1", 2)])));
+    }
+
+    // Keeps the clock it is told to and sends notes to other nodes on request
+    struct Clocked {
+        node_id: NodeId,
+        msg_seq_id: MsgId,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Request {
+        SetClock { clock: Clock },
+        Notify { to: NodeId },
+        Note {},
+        Time {},
+    }
+
+    impl Payload for Request {}
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Reply {
+        SetClockOk {},
+        NotifyOk {},
+        Note {},
+        // a payload field named like the stamp of the body
+        TimeOk { clock: Option<Clock> },
+    }
+
+    impl Payload for Reply {}
+
+    impl EventNode for Clocked {
+        type Msg<'a> = Request;
+
+        fn new(Init::Init { node_id, .. }: Init, _: Output) -> Self {
+            Self {
+                node_id,
+                msg_seq_id: MsgId::ONE,
+            }
+        }
+
+        fn on_message(
+            &mut self,
+            ctx: &mut Context<Self>,
+            request: &Message<Request>,
+        ) -> Result<()> {
+            let reply = match request.payload() {
+                Request::SetClock { clock } => {
+                    ctx.set_clock(clock.clone());
+                    Reply::SetClockOk {}
+                }
+                Request::Notify { to } => {
+                    let note = Message::new(
+                        self.node_id.clone(),
+                        to.clone(),
+                        Some(&mut self.msg_seq_id),
+                        Reply::Note {},
+                    );
+                    ctx.send(note)?;
+                    Reply::NotifyOk {}
+                }
+                Request::Note {} => return Ok(()),
+                Request::Time {} => Reply::TimeOk {
+                    clock: ctx.clock().cloned(),
+                },
+            };
+            ctx.respond(request, Some(&mut self.msg_seq_id), reply)
+        }
+    }
+
+    fn time(net: &mut Network, node: &NodeId) -> Clock {
+        let reply = net.call(node, json!({"type": "time"}));
+        assert!(reply.clock().is_some(), "replies are stamped");
+        serde_json::from_value(reply.payload()["clock"].clone()).unwrap()
+    }
+
+    #[test]
+    fn stamped_messages() {
+        let mut net = Network::new(0);
+        let nodes = net.spawn::<Clocked>(3);
+        for (node, clock) in nodes.iter().zip([
+            json!({"vector": {}}),
+            json!({"vector": {}}),
+            json!({"lamport": 0}),
+        ]) {
+            let reply = net.call(node, json!({"type": "set_clock", "clock": clock}));
+            assert_eq!(reply.payload()["type"], "set_clock_ok");
+        }
+
+        let before = time(&mut net, &nodes[0]);
+        net.call(&nodes[0], json!({"type": "notify", "to": nodes[1]}));
+        net.run_until_idle();
+        let (Clock::Vector(sent), Clock::Vector(received)) = (before, time(&mut net, &nodes[1]))
+        else {
+            panic!("n0 and n1 keep vector clocks");
+        };
+        // the note happened before n1 answered, n1 has seen n0's events up to the send
+        assert!(sent < received);
+        assert_eq!(received.get(&nodes[0]), 3);
+
+        // n0 never heard from n1 since
+        let Clock::Vector(later) = time(&mut net, &nodes[0]) else {
+            panic!("n0 keeps a vector clock");
+        };
+        assert!(later.concurrent(&received));
+
+        // a vector stamp doesn't move a lamport clock, only the answer to set_clock did
+        net.call(&nodes[0], json!({"type": "notify", "to": nodes[2]}));
+        net.run_until_idle();
+        assert_eq!(time(&mut net, &nodes[2]), Clock::Lamport(Lamport(1)));
+    }
+
+    #[test]
+    fn payload_field_named_clock() {
+        let line = r#"{"src":"c1","dest":"n0","body":{"type":"time_ok","clock":5}}"#;
+        let msg = serde_json::from_str::<Message<Value>>(line).unwrap();
+        assert_eq!(msg.clock(), None);
+        assert_eq!(msg.payload()["clock"], 5);
+    }
+}
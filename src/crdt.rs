@@ -0,0 +1,404 @@
+//! Conflict-free replicated data types, replicas that merged the same states agree
+//! no matter in which order or how often the states arrived.
+//!
+//! The types can be replicated state based, by sending the whole state in a message, or
+//! delta based, every update returns a delta of the same type that only holds what the
+//! update changed and merges like a whole state.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{clock::Hlc, NodeId};
+
+pub trait Merge {
+    /// Has to be commutative, associative and idempotent.
+    fn merge(&mut self, other: &Self);
+}
+
+/// Counter that only grows, every node counts its own increments and the value is the sum.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter(BTreeMap<NodeId, u64>);
+
+impl GCounter {
+    pub fn increment(&mut self, node: &NodeId, delta: u64) -> Self {
+        let count = self.0.entry(node.clone()).or_default();
+        *count += delta;
+        Self(BTreeMap::from([(node.clone(), *count)]))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+impl Merge for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node, &count) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+}
+
+/// Counter that can also shrink, increments and decrements are counted separately.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, node: &NodeId, delta: i64) -> Self {
+        let mut changed = Self::default();
+        if delta >= 0 {
+            changed.increments = self.increments.increment(node, delta.unsigned_abs());
+        } else {
+            changed.decrements = self.decrements.increment(node, delta.unsigned_abs());
+        }
+        changed
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Merge for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// Set that elements can only be added to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T: Ord>(BTreeSet<T>);
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self(BTreeSet::new())
+    }
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        self.0.insert(value.clone());
+        Self(BTreeSet::from([value]))
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.0.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T: Ord + Clone> Merge for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.0.extend(other.0.iter().cloned());
+    }
+}
+
+/// Set that elements can be removed from once, a removed element can't be added again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TwoPhaseSet<T: Ord> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Ord> Default for TwoPhaseSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> TwoPhaseSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        Self {
+            added: self.added.insert(value),
+            removed: GSet::default(),
+        }
+    }
+
+    /// Elements that weren't added here are left alone, the delta is empty then.
+    pub fn remove(&mut self, value: T) -> Self {
+        if !self.added.contains(&value) {
+            return Self::default();
+        }
+        Self {
+            added: GSet::default(),
+            removed: self.removed.insert(value),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.added.contains(value) && !self.removed.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added
+            .iter()
+            .filter(|value| !self.removed.contains(value))
+    }
+}
+
+impl<T: Ord + Clone> Merge for TwoPhaseSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+}
+
+/// Identifies one insert into an [`OrSet`], the node and how many inserts it did before.
+pub type Tag = (NodeId, u64);
+
+/// Observed-remove set, a remove only takes back the inserts it has seen,
+/// so an element inserted concurrently with its removal stays.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet<T: Ord> {
+    inserted: BTreeSet<(T, Tag)>,
+    removed: BTreeSet<Tag>,
+    // inserts per node, to tag the next one
+    inserts: GCounter,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            inserted: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            inserts: GCounter::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    pub fn insert(&mut self, node: &NodeId, value: T) -> Self {
+        let inserts = self.inserts.increment(node, 1);
+        let tag = (node.clone(), self.inserts.0[node]);
+        self.inserted.insert((value.clone(), tag.clone()));
+        Self {
+            inserted: BTreeSet::from([(value, tag)]),
+            removed: BTreeSet::new(),
+            inserts,
+        }
+    }
+
+    pub fn remove(&mut self, value: &T) -> Self {
+        let tags = self.tags(value).cloned().collect::<BTreeSet<_>>();
+        self.removed.extend(tags.iter().cloned());
+        Self {
+            inserted: BTreeSet::new(),
+            removed: tags,
+            inserts: GCounter::default(),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.tags(value).next().is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut last = None;
+        self.inserted
+            .iter()
+            .filter(|(_, tag)| !self.removed.contains(tag))
+            .map(|(value, _)| value)
+            .filter(move |value| last.replace(*value) != Some(*value))
+    }
+
+    // the inserts of `value` that weren't removed yet
+    fn tags<'a>(&'a self, value: &'a T) -> impl Iterator<Item = &'a Tag> {
+        self.inserted
+            .range((value.clone(), (NodeId(String::new()), 0))..)
+            .take_while(move |(inserted, _)| inserted == value)
+            .map(|(_, tag)| tag)
+            .filter(|tag| !self.removed.contains(tag))
+    }
+}
+
+impl<T: Ord + Clone> Merge for OrSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.inserted.extend(other.inserted.iter().cloned());
+        self.removed.extend(other.removed.iter().cloned());
+        self.inserts.merge(&other.inserts);
+    }
+}
+
+/// Register that keeps the value written last according to a hybrid logical clock,
+/// writes at the same time are ordered by node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    stamp: (Hlc, Option<NodeId>),
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            stamp: (Hlc::default(), None),
+        }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    /// The delta is the whole register.
+    pub fn set(&mut self, node: &NodeId, value: T) -> Self {
+        // ahead of the current value even if our wall clock is behind the writer's
+        self.stamp.0.tick(Hlc::physical_now());
+        self.stamp.1 = Some(node.clone());
+        self.value = Some(value);
+        self.clone()
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+impl<T: Clone> Merge for LwwRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if other.stamp > self.stamp {
+            *self = other.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    fn node(name: &str) -> NodeId {
+        NodeId(name.to_owned())
+    }
+
+    // Merging in either order gives the same state, merging again changes nothing and the
+    // state survives a trip through json
+    fn check_laws<T>(a: &T, b: &T)
+    where
+        T: Merge + Clone + PartialEq + std::fmt::Debug + Serialize + DeserializeOwned,
+    {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba);
+
+        let mut again = ab.clone();
+        again.merge(b);
+        again.merge(a);
+        assert_eq!(again, ab);
+
+        let json = serde_json::to_string(&ab).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), ab);
+    }
+
+    #[test]
+    fn counters() {
+        let (n1, n2) = (node("n1"), node("n2"));
+
+        let mut a = GCounter::default();
+        let mut b = GCounter::default();
+        a.increment(&n1, 3);
+        let delta = b.increment(&n2, 4);
+        b.increment(&n2, 1);
+        check_laws(&a, &b);
+        a.merge(&b);
+        assert_eq!(a.value(), 8);
+        // deltas are states as well, an old one changes nothing
+        a.merge(&delta);
+        assert_eq!(a.value(), 8);
+
+        let mut a = PnCounter::default();
+        let mut b = PnCounter::default();
+        a.add(&n1, 5);
+        b.add(&n2, -7);
+        let delta = b.add(&n2, 1);
+        check_laws(&a, &b);
+        a.merge(&delta);
+        assert_eq!(a.value(), 6);
+        a.merge(&b);
+        assert_eq!(a.value(), -1);
+    }
+
+    #[test]
+    fn sets() {
+        let (n1, n2) = (node("n1"), node("n2"));
+
+        let mut a = GSet::default();
+        let mut b = GSet::default();
+        a.insert(1);
+        b.insert(2);
+        let delta = b.insert(3);
+        check_laws(&a, &b);
+        a.merge(&delta);
+        assert_eq!(a.iter().copied().collect::<Vec<_>>(), [1, 3]);
+
+        let mut a = TwoPhaseSet::default();
+        a.insert(1);
+        a.insert(2);
+        let mut b = a.clone();
+        b.remove(1);
+        // once removed an element can't come back
+        a.insert(1);
+        check_laws(&a, &b);
+        a.merge(&b);
+        assert!(!a.contains(&1));
+        assert_eq!(a.iter().copied().collect::<Vec<_>>(), [2]);
+
+        let mut a = OrSet::default();
+        a.insert(&n1, "x".to_owned());
+        let mut b = a.clone();
+        // the concurrent insert wasn't observed by the remove, so it wins
+        b.remove(&"x".to_owned());
+        a.insert(&n1, "x".to_owned());
+        a.insert(&n2, "y".to_owned());
+        check_laws(&a, &b);
+        a.merge(&b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), ["x", "y"]);
+        let delta = a.remove(&"x".to_owned());
+        b.merge(&a);
+        assert!(!b.contains(&"x".to_owned()));
+        b.merge(&delta);
+        assert_eq!(b.iter().collect::<Vec<_>>(), ["y"]);
+    }
+
+    #[test]
+    fn register() {
+        let (n1, n2) = (node("n1"), node("n2"));
+
+        let mut a = LwwRegister::default();
+        let mut b = LwwRegister::default();
+        assert_eq!(a.get(), None);
+        a.set(&n1, 1);
+        b.merge(&a);
+        b.set(&n2, 2);
+        check_laws(&a, &b);
+        a.merge(&b);
+        assert_eq!(a.get(), Some(&2));
+
+        // writes in the same millisecond are ordered by the clock's logical part
+        let delta = a.set(&n1, 3);
+        b.merge(&delta);
+        assert_eq!(b.get(), Some(&3));
+    }
+}
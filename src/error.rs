@@ -0,0 +1,94 @@
+use std::fmt::Display;
+
+use crate::{ErrorCode, Message, Output, Payload};
+
+pub type Result<T, E = NodeError> = std::result::Result<T, E>;
+
+/// Why handling a message failed, the runtime answers the request depending on the kind.
+#[derive(Debug)]
+pub enum NodeError {
+    /// The input wasn't a valid message, requests are answered with `MalformedRequest`.
+    Protocol(serde_json::Error),
+    /// The handler rejected the request, it is answered with `code` and `text`.
+    Handler {
+        code: ErrorCode,
+        text: Option<String>,
+    },
+    /// Reading or writing messages failed, this stops the node.
+    Transport(std::io::Error),
+}
+
+impl NodeError {
+    pub fn handler(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self::Handler {
+            code,
+            text: Some(text.into()),
+        }
+    }
+}
+
+impl Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Protocol(err) => write!(f, "malformed message: {err}"),
+            Self::Handler {
+                code,
+                text: Some(text),
+            } => write!(f, "{code:?}: {text}"),
+            Self::Handler { code, text: None } => write!(f, "{code:?}"),
+            Self::Transport(err) => write!(f, "transport failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Protocol(err) => Some(err),
+            Self::Handler { .. } => None,
+            Self::Transport(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for NodeError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Transport(err)
+    }
+}
+
+impl From<serde_json::Error> for NodeError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Protocol(err)
+    }
+}
+
+impl From<ErrorCode> for NodeError {
+    fn from(code: ErrorCode) -> Self {
+        Self::Handler { code, text: None }
+    }
+}
+
+/// Answer `request` according to `err`, without a request to answer only transport errors
+/// matter, the others are logged to stderr.
+pub(crate) fn report<M: Payload>(
+    request: Option<&Message<M>>,
+    err: NodeError,
+    output: &mut Output,
+) -> Result<()> {
+    match (err, request) {
+        (NodeError::Transport(err), _) => Err(NodeError::Transport(err)),
+        (NodeError::Protocol(err), Some(request)) => {
+            request.respond_error(output, ErrorCode::MalformedRequest, Some(err.to_string()))?;
+            Ok(())
+        }
+        (NodeError::Handler { code, text }, Some(request)) => {
+            request.respond_error(output, code, text)?;
+            Ok(())
+        }
+        (err, None) => {
+            eprintln!("{err}");
+            Ok(())
+        }
+    }
+}
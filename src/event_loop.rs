@@ -0,0 +1,583 @@
+//! Single threaded runtime that multiplexes incoming messages, replies and timers.
+//!
+//! Unlike [`crate::run`] the node doesn't need its own threads for periodic work,
+//! it registers [`Timer`]s through the [`Context`] and gets called back with `on_tick`,
+//! requests sent with [`Context::rpc`] get their reply passed to a callback.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{stdin, Write},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    clock::Clock, error, parse, rng::Rng, Body, ErrorCode, EventNode, Init, InitOk, Message, MsgId,
+    NodeId, Output, Payload, Result,
+};
+
+/// A named timer, setting a timer with the name of an active one replaces it.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    name: &'static str,
+    delay: Duration,
+    period: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Timer {
+    pub fn once(name: &'static str, delay: Duration) -> Self {
+        Self {
+            name,
+            delay,
+            period: None,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Fires every `period`, the first time one `period` after being set.
+    /// Panics for a zero `period`, the timer would be due again right after firing.
+    pub fn every(name: &'static str, period: Duration) -> Self {
+        assert!(!period.is_zero(), "timer `{name}` needs a nonzero period");
+        Self {
+            name,
+            delay: period,
+            period: Some(period),
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Delay each firing by a random amount up to `jitter`,
+    /// so nodes started together don't all tick at the same time.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum Event<M> {
+    Message(Message<M>),
+    // a message with `in_reply_to` set, i.e. the answer to something we sent
+    Reply(Message<M>),
+    Timer(&'static str),
+}
+
+pub struct Context<'a, N> {
+    now: Duration,
+    state: &'a mut State<N>,
+}
+
+impl<N: EventNode> Context<'_, N> {
+    /// Time since the runtime started, virtual when running in the simulator.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_id(&self) -> &NodeId {
+        &self.state.node_id
+    }
+
+    pub fn output(&mut self) -> &mut Output {
+        &mut self.state.output
+    }
+
+    /// Keep `clock` from now on, see [`crate::clock`].
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.state.clock = Some(clock);
+    }
+
+    pub fn clock(&self) -> Option<&Clock> {
+        self.state.clock.as_ref()
+    }
+
+    /// Send `msg`, stamped with the node's clock if it keeps one.
+    pub fn send<P>(&mut self, msg: Message<P>) -> Result<()>
+    where
+        P: Payload,
+        Message<P>: Serialize,
+    {
+        self.state.stamp(msg).send(&mut self.state.output)?;
+        Ok(())
+    }
+
+    /// Like [`Message::respond`], stamped with the node's clock if it keeps one.
+    pub fn respond<P, R>(
+        &mut self,
+        request: &Message<P>,
+        msg_id: Option<&mut MsgId>,
+        payload: R,
+    ) -> Result<()>
+    where
+        P: Payload,
+        R: Payload,
+        Message<R>: Serialize,
+    {
+        self.send(request.reply(msg_id, payload))
+    }
+
+    pub fn set_timer(&mut self, timer: Timer) {
+        self.state.timers.set(self.now, timer);
+    }
+
+    /// Returns whether a timer with that name was active.
+    pub fn cancel_timer(&mut self, name: &str) -> bool {
+        self.state.timers.cancel(name)
+    }
+
+    /// Send `payload` to `dest` and call `callback` with the decoded reply,
+    /// an `error` reply is passed as its [`ErrorCode`] and if no reply arrives
+    /// within `timeout` the callback gets [`ErrorCode::Timeout`]. An `Err` returned
+    /// from the callback is only logged.
+    pub fn rpc<P, R, F>(
+        &mut self,
+        dest: NodeId,
+        msg_id: &mut MsgId,
+        payload: P,
+        timeout: Duration,
+        callback: F,
+    ) -> Result<MsgId>
+    where
+        P: Payload,
+        Message<P>: Serialize,
+        R: DeserializeOwned,
+        F: FnOnce(&mut N, &mut Context<N>, Result<R, ErrorCode>) -> Result<()> + 'static,
+    {
+        let msg = Message::new(self.state.node_id.clone(), dest, Some(msg_id), payload);
+        let id = msg.id().expect("message was created with an id");
+        self.state.rpcs.insert(
+            id,
+            self.now + timeout,
+            Box::new(move |node, ctx, reply| callback(node, ctx, reply.and_then(decode))),
+        );
+        self.send(msg)?;
+        Ok(id)
+    }
+}
+
+fn decode<R: DeserializeOwned>(payload: Value) -> Result<R, ErrorCode> {
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum ErrorReply {
+        Error { code: ErrorCode },
+    }
+
+    if let Ok(ErrorReply::Error { code }) = ErrorReply::deserialize(&payload) {
+        return Err(code);
+    }
+    R::deserialize(payload).map_err(|_| ErrorCode::MalformedRequest)
+}
+
+pub(crate) struct Timers {
+    rng: Rng,
+    scheduled: u64,
+    due: BTreeMap<(Duration, u64), &'static str>,
+    active: HashMap<&'static str, ((Duration, u64), Timer)>,
+}
+
+impl Timers {
+    pub(crate) fn new(rng: Rng) -> Self {
+        Self {
+            rng,
+            scheduled: 0,
+            due: BTreeMap::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    fn set(&mut self, now: Duration, timer: Timer) {
+        self.cancel(timer.name);
+        self.schedule(now + timer.delay, timer);
+    }
+
+    fn schedule(&mut self, at: Duration, timer: Timer) {
+        let key = (
+            at + self.rng.duration(&(Duration::ZERO..=timer.jitter)),
+            self.scheduled,
+        );
+        self.scheduled += 1;
+        self.due.insert(key, timer.name);
+        self.active.insert(timer.name, (key, timer));
+    }
+
+    fn cancel(&mut self, name: &str) -> bool {
+        match self.active.remove(name) {
+            Some((key, _)) => self.due.remove(&key).is_some(),
+            None => false,
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Duration> {
+        self.due.first_key_value().map(|(&(at, _), _)| at)
+    }
+
+    pub(crate) fn pop_due(&mut self, now: Duration) -> Option<&'static str> {
+        let (&(at, _), _) = self.due.first_key_value()?;
+        if at > now {
+            return None;
+        }
+        let (_, name) = self.due.pop_first()?;
+        let (_, timer) = self.active.remove(name).expect("due timers are active");
+        if let Some(period) = timer.period {
+            self.schedule(now + period, timer);
+        }
+        Some(name)
+    }
+}
+
+type Callback<N> = Box<dyn FnOnce(&mut N, &mut Context<N>, Result<Value, ErrorCode>) -> Result<()>>;
+
+struct Rpcs<N> {
+    pending: HashMap<MsgId, (Duration, Callback<N>)>,
+    deadlines: BTreeMap<(Duration, MsgId), ()>,
+    // replies to these can still arrive, but the callback already got a timeout,
+    // forgotten after `LATE_REPLY_GRACE` so partitions don't pile them up
+    expired: HashSet<MsgId>,
+    expired_at: VecDeque<(Duration, MsgId)>,
+}
+
+impl<N> Rpcs<N> {
+    // replies later than this are handled like any other message
+    const LATE_REPLY_GRACE: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            deadlines: BTreeMap::new(),
+            expired: HashSet::new(),
+            expired_at: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, id: MsgId, deadline: Duration, callback: Callback<N>) {
+        self.deadlines.insert((deadline, id), ());
+        self.pending.insert(id, (deadline, callback));
+    }
+
+    fn resolve(&mut self, id: MsgId) -> Option<Callback<N>> {
+        let (deadline, callback) = self.pending.remove(&id)?;
+        self.deadlines.remove(&(deadline, id));
+        Some(callback)
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.deadlines.first_key_value().map(|(&(at, _), _)| at)
+    }
+
+    fn pop_expired(&mut self, now: Duration) -> Option<Callback<N>> {
+        let (&(at, id), _) = self.deadlines.first_key_value()?;
+        if at > now {
+            return None;
+        }
+        self.expired.insert(id);
+        self.expired_at.push_back((now, id));
+        self.resolve(id)
+    }
+
+    fn forget_expired(&mut self, now: Duration) {
+        while let Some(&(at, id)) = self.expired_at.front() {
+            if at + Self::LATE_REPLY_GRACE > now {
+                break;
+            }
+            self.expired.remove(&id);
+            self.expired_at.pop_front();
+        }
+    }
+}
+
+pub(crate) struct State<N> {
+    node_id: NodeId,
+    output: Output,
+    clock: Option<Clock>,
+    timers: Timers,
+    rpcs: Rpcs<N>,
+}
+
+impl<N> State<N> {
+    fn stamp<P: Payload>(&mut self, msg: Message<P>) -> Message<P> {
+        match &mut self.clock {
+            Some(clock) => {
+                let stamp = clock.tick(&self.node_id);
+                msg.with_clock(stamp)
+            }
+            None => msg,
+        }
+    }
+
+    fn observe(&mut self, stamp: Option<&Clock>) {
+        if let (Some(clock), Some(stamp)) = (&mut self.clock, stamp) {
+            clock.merge(stamp, &self.node_id);
+        }
+    }
+}
+
+/// A node together with the runtime state its [`Context`] needs,
+/// shared by [`run`] and the simulator.
+pub(crate) struct Driver<N> {
+    node: N,
+    state: State<N>,
+}
+
+impl<N: EventNode> Driver<N> {
+    pub(crate) fn new(
+        new: impl FnOnce(Init, Output) -> N,
+        init: Init,
+        output: Output,
+        rng: Rng,
+    ) -> Self {
+        let Init::Init { node_id, .. } = &init;
+        let node_id = node_id.clone();
+        Self {
+            node: new(init, output.clone()),
+            state: State {
+                node_id,
+                output,
+                clock: None,
+                timers: Timers::new(rng),
+                rpcs: Rpcs::new(),
+            },
+        }
+    }
+
+    // what the node sends while starting goes out right away, not with the first input
+    pub(crate) fn start(&mut self, now: Duration) -> Result<()> {
+        let mut ctx = Context {
+            now,
+            state: &mut self.state,
+        };
+        self.node.start(&mut ctx)?;
+        self.flush()
+    }
+
+    pub(crate) fn deliver(&mut self, now: Duration, line: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Envelope {
+            body: Body<serde::de::IgnoredAny>,
+        }
+
+        let mut ctx = Context {
+            now,
+            state: &mut self.state,
+        };
+
+        ctx.state.rpcs.forget_expired(now);
+        if !ctx.state.rpcs.pending.is_empty() || !ctx.state.rpcs.expired.is_empty() {
+            let in_reply_to = serde_json::from_str::<Envelope>(line)
+                .ok()
+                .and_then(|envelope| envelope.body.in_reply_to);
+            if let Some(in_reply_to) = in_reply_to {
+                if ctx.state.rpcs.expired.remove(&in_reply_to) {
+                    return Ok(());
+                }
+                if let Some(callback) = ctx.state.rpcs.resolve(in_reply_to) {
+                    let reply: Message<Value> = serde_json::from_str(line)?;
+                    ctx.state.observe(reply.clock());
+                    if let Err(err) = callback(&mut self.node, &mut ctx, Ok(reply.body.payload)) {
+                        error::report::<N::Msg<'_>>(None, err, &mut ctx.state.output)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        let Some(msg) = parse::<N::Msg<'_>>(line, &mut ctx.state.output)? else {
+            return Ok(());
+        };
+        ctx.state.observe(msg.clock());
+        let event = if msg.in_response_to().is_some() {
+            Event::Reply(msg)
+        } else {
+            Event::Message(msg)
+        };
+        if let Err(err) = self.node.on_event(&mut ctx, &event) {
+            let (Event::Message(msg) | Event::Reply(msg)) = &event else {
+                unreachable!("event was constructed from a message")
+            };
+            error::report(Some(msg), err, &mut ctx.state.output)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.state.output.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn shutdown(&mut self, now: Duration) -> Result<()> {
+        let mut ctx = Context {
+            now,
+            state: &mut self.state,
+        };
+        if let Err(err) = self.node.shutdown(&mut ctx) {
+            error::report::<N::Msg<'_>>(None, err, &mut ctx.state.output)?;
+        }
+        ctx.state.output.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Duration> {
+        match (
+            self.state.timers.next_deadline(),
+            self.state.rpcs.next_deadline(),
+        ) {
+            (Some(timer), Some(rpc)) => Some(timer.min(rpc)),
+            (timer, rpc) => timer.or(rpc),
+        }
+    }
+
+    pub(crate) fn fire(&mut self, now: Duration) -> Result<()> {
+        let mut ctx = Context {
+            now,
+            state: &mut self.state,
+        };
+        // there is no request to answer, so only transport errors get through
+        while let Some(timer) = ctx.state.timers.pop_due(now) {
+            if let Err(err) = self.node.on_event(&mut ctx, &Event::Timer(timer)) {
+                error::report::<N::Msg<'_>>(None, err, &mut ctx.state.output)?;
+            }
+        }
+        ctx.state.rpcs.forget_expired(now);
+        while let Some(callback) = ctx.state.rpcs.pop_expired(now) {
+            if let Err(err) = callback(&mut self.node, &mut ctx, Err(ErrorCode::Timeout)) {
+                error::report::<N::Msg<'_>>(None, err, &mut ctx.state.output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run<N: EventNode>() -> Result<()> {
+    run_with(N::new)
+}
+
+/// Like [`run`], but the node is created by `new` instead of [`EventNode::new`],
+/// e.g. to pass it options the binary read from its arguments or environment.
+pub fn run_with<N: EventNode>(new: impl FnOnce(Init, Output) -> N) -> Result<()> {
+    let started = Instant::now();
+    let mut output = Output::stdout();
+
+    let init: Message<Init> = {
+        let mut init = String::new();
+        stdin().read_line(&mut init)?;
+        serde_json::from_str(&init)?
+    };
+
+    init.respond(&mut output, Some(&mut MsgId(0)), InitOk::InitOk {})?;
+    output.flush()?;
+
+    let mut driver = Driver::new(new, init.body.payload, output, Rng::from_entropy());
+    driver.start(started.elapsed())?;
+
+    let (lines, incoming) = mpsc::channel();
+    // handled lines go back to the reader, so their buffers are reused
+    let (handled, spare) = mpsc::channel::<String>();
+    let reader = std::thread::spawn(move || -> Result<()> {
+        let stdin = stdin();
+        loop {
+            let mut line = spare.try_recv().unwrap_or_default();
+            line.clear();
+            if stdin.read_line(&mut line)? == 0 || lines.send(line).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    loop {
+        let received = match driver.next_deadline() {
+            Some(deadline) => incoming.recv_timeout(deadline.saturating_sub(started.elapsed())),
+            None => incoming.recv().map_err(RecvTimeoutError::from),
+        };
+        match received {
+            Ok(line) => {
+                driver.deliver(started.elapsed(), &line)?;
+                let _ = handled.send(line);
+                // handle everything that is already waiting before flushing the replies
+                for line in incoming.try_iter() {
+                    driver.deliver(started.elapsed(), &line)?;
+                    let _ = handled.send(line);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // stdin was closed or couldn't be read anymore
+            Err(RecvTimeoutError::Disconnected) => {
+                driver.shutdown(started.elapsed())?;
+                return reader.join().expect("stdin reader panicked");
+            }
+        }
+        driver.fire(started.elapsed())?;
+        driver.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::BufWriter,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Hello {
+        Hello {},
+    }
+
+    impl Payload for Hello {}
+
+    // Greets n1 as soon as it starts
+    struct Greeter;
+
+    impl EventNode for Greeter {
+        type Msg<'a> = Hello;
+
+        fn new(_: Init, _: Output) -> Self {
+            Self
+        }
+
+        fn start(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+            let to = NodeId(String::from("n1"));
+            let hello = Message::new(ctx.node_id().clone(), to, None, Hello::Hello {});
+            ctx.send(hello)
+        }
+
+        fn on_message(&mut self, _: &mut Context<Self>, _: &Message<Hello>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn start_flushes() {
+        let written = Written::default();
+        let init = Init::Init {
+            node_id: NodeId(String::from("n0")),
+            node_ids: Vec::new(),
+        };
+        // buffered like stdout
+        let output = Output::new(BufWriter::new(written.clone()));
+        let mut driver = Driver::new(Greeter::new, init, output, Rng::new(0));
+        driver.start(Duration::ZERO).unwrap();
+
+        let line = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        let hello = serde_json::from_str::<Message<Hello>>(&line).unwrap();
+        assert_eq!(hello.dst(), &NodeId(String::from("n1")));
+    }
+}
@@ -0,0 +1,150 @@
+//! A node that replicates a CRDT by gossip: clients update the local replica, which
+//! is periodically sent whole to the peers that may not have seen all of it, they merge
+//! it and answer with what they have now. Updates never wait for anyone and the replicas
+//! converge once gossip gets through again.
+
+use std::{collections::HashMap, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    crdt::Merge,
+    event_loop::{Context, Timer},
+    EventNode, Init, Message, MsgId, NodeId, Output, Payload, Result,
+};
+
+/// State that can be gossiped as a whole, every [`Merge`] type that can be sent is.
+pub trait Replicated: Merge + Clone + Default + PartialEq + Serialize + DeserializeOwned {
+    /// Whether merging `other` would change nothing.
+    fn contains(&self, other: &Self) -> bool;
+}
+
+impl<T> Replicated for T
+where
+    T: Merge + Clone + Default + PartialEq + Serialize + DeserializeOwned,
+{
+    fn contains(&self, other: &Self) -> bool {
+        let mut merged = self.clone();
+        merged.merge(other);
+        merged == *self
+    }
+}
+
+/// What clients can do with the replica of a [`GossipNode`].
+pub trait Operations {
+    type State: Replicated;
+    type Request: DeserializeOwned + Payload;
+    type Reply: Serialize + Payload;
+
+    /// Handle a client request on the local replica of `node`.
+    fn apply(state: &mut Self::State, node: &NodeId, request: &Self::Request) -> Self::Reply;
+}
+
+/// The messages of a [`GossipNode`], its own replication or client requests `R`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Messages<R, C> {
+    Replication(Replication<C>),
+    Client(R),
+}
+
+impl<R, C> Payload for Messages<R, C> {}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Replication<C> {
+    Gossip { state: C },
+    GossipOk { state: C },
+}
+
+impl<C> Payload for Replication<C> {}
+
+/// Replicates [`Operations::State`], every node gossips with all others.
+pub struct GossipNode<O: Operations> {
+    node_id: NodeId,
+    peers: Vec<NodeId>,
+    msg_seq_id: MsgId,
+    state: O::State,
+    // the newest state each peer answered with
+    known: HashMap<NodeId, O::State>,
+    gossip_interval: Duration,
+    output: Output,
+}
+
+impl<O: Operations> EventNode for GossipNode<O> {
+    type Msg<'a> = Messages<O::Request, O::State>;
+
+    fn new(Init::Init { node_id, node_ids }: Init, output: Output) -> Self {
+        let peers = node_ids
+            .into_iter()
+            .filter(|peer| *peer != node_id)
+            .collect();
+        Self {
+            node_id,
+            peers,
+            msg_seq_id: MsgId::ONE,
+            state: O::State::default(),
+            known: HashMap::new(),
+            gossip_interval: Duration::from_millis(100),
+            output,
+        }
+    }
+
+    fn start(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.set_timer(
+            Timer::every("gossip", self.gossip_interval).with_jitter(self.gossip_interval / 5),
+        );
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> Result<()> {
+        match request.payload() {
+            Messages::Client(op) => {
+                let reply = O::apply(&mut self.state, &self.node_id, op);
+                request.respond(&mut self.output, Some(&mut self.msg_seq_id), reply)?;
+            }
+            Messages::Replication(Replication::Gossip { state }) => {
+                self.state.merge(state);
+                request.respond(
+                    &mut self.output,
+                    Some(&mut self.msg_seq_id),
+                    Replication::GossipOk {
+                        state: self.state.clone(),
+                    },
+                )?;
+            }
+            Messages::Replication(Replication::GossipOk { state }) => {
+                self.state.merge(state);
+                self.known.insert(request.src().clone(), state.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn on_tick(&mut self, _ctx: &mut Context<Self>, _timer: &str) -> Result<()> {
+        // lost gossip is sent again on the next tick, until the peer confirmed it
+        for peer in &self.peers {
+            if self
+                .known
+                .get(peer)
+                .is_some_and(|known| known.contains(&self.state))
+            {
+                continue;
+            }
+            Message::new(
+                self.node_id.clone(),
+                peer.clone(),
+                Some(&mut self.msg_seq_id),
+                Replication::Gossip {
+                    state: self.state.clone(),
+                },
+            )
+            .send(&mut self.output)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Request/response nodes that return their reply instead of writing it,
+//! so handling a request can be tested without an [`Output`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Init, Message, MsgId, Node, Output, Payload, Result};
+
+pub trait Handler: Sized {
+    /// Derive it with `#[payload(reply = ...)]` naming [`Handler::Reply`], so every request
+    /// is checked to have a reply.
+    type Request<'a>: Deserialize<'a> + crate::Request<Reply = Self::Reply>;
+    type Reply: Serialize + Payload;
+
+    fn new(init: Init) -> Self;
+
+    /// The reply is sent back to the sender of `request`, an `Err` is answered
+    /// like one returned by [`Node::process`], e.g. `Err(ErrorCode::Abort.into())`.
+    fn handle(&mut self, request: &Message<Self::Request<'_>>) -> Result<Self::Reply>;
+}
+
+/// Runs a [`Handler`] as a [`Node`] node, it allocates the `msg_id` of each reply
+/// and sets its `in_reply_to`, e.g. `run::<Responder<EchoNode>>()`.
+pub struct Responder<H> {
+    handler: H,
+    msg_seq_id: MsgId,
+    output: Output,
+}
+
+impl<H> Responder<H> {
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+}
+
+impl<H: Handler> Node for Responder<H> {
+    type Msg<'a> = H::Request<'a>;
+
+    fn new(init: Init, output: Output) -> Self {
+        Self {
+            handler: H::new(init),
+            msg_seq_id: MsgId::ONE,
+            output,
+        }
+    }
+
+    fn process(&mut self, request: &Message<Self::Msg<'_>>) -> Result<()> {
+        let reply = self.handler.handle(request)?;
+        request.respond(&mut self.output, Some(&mut self.msg_seq_id), reply)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+//! Unique id generators, every node generates its ids without talking to the others.
+//!
+//! Ids of one generator sort in the order they were generated, as strings as well.
+
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{rng::Rng, NodeId};
+
+pub trait IdGenerator {
+    fn generate(&mut self) -> io::Result<String>;
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// 64 bit ids made of 41 bits of milliseconds since 2024, a 10 bit worker and a 12 bit
+/// sequence within the millisecond.
+pub struct Snowflake {
+    worker: u64,
+    last: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    const EPOCH: u64 = 1_704_067_200_000;
+    const WORKER_BITS: u32 = 10;
+    const SEQUENCE_BITS: u32 = 12;
+
+    pub fn new(worker: u64) -> Self {
+        assert!(
+            worker < 1 << Self::WORKER_BITS,
+            "snowflake workers have to be < 1024"
+        );
+        Self {
+            worker,
+            last: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        // never go back in time, even if the wall clock does
+        let now = unix_millis().saturating_sub(Self::EPOCH).max(self.last);
+        if now > self.last {
+            self.last = now;
+            self.sequence = 0;
+        } else {
+            self.sequence += 1;
+            if self.sequence == 1 << Self::SEQUENCE_BITS {
+                // the millisecond is used up, borrow the next one
+                self.last += 1;
+                self.sequence = 0;
+            }
+        }
+        self.last << (Self::WORKER_BITS + Self::SEQUENCE_BITS)
+            | self.worker << Self::SEQUENCE_BITS
+            | self.sequence
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn generate(&mut self) -> io::Result<String> {
+        // padded so the strings sort like the numbers
+        Ok(format!("{:020}", self.next_id()))
+    }
+}
+
+/// Version 7 UUIDs, the 12 bits after the millisecond timestamp count up within the
+/// millisecond and the rest is random, see RFC 9562.
+pub struct UuidV7 {
+    rng: Rng,
+    last: u64,
+    counter: u16,
+}
+
+impl UuidV7 {
+    pub fn new() -> Self {
+        Self {
+            rng: Rng::from_entropy(),
+            last: 0,
+            counter: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> u128 {
+        let now = unix_millis().max(self.last);
+        if now > self.last {
+            self.last = now;
+            self.counter = 0;
+        } else {
+            self.counter += 1;
+            if self.counter == 1 << 12 {
+                self.last += 1;
+                self.counter = 0;
+            }
+        }
+        let random = self.rng.next_u64() & ((1 << 62) - 1);
+        (self.last as u128) << 80
+            | 0x7 << 76
+            | (self.counter as u128) << 64
+            | 0b10 << 62
+            | random as u128
+    }
+}
+
+impl Default for UuidV7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for UuidV7 {
+    fn generate(&mut self) -> io::Result<String> {
+        let id = self.next_id();
+        Ok(format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            id >> 96,
+            (id >> 80) & 0xffff,
+            (id >> 64) & 0xffff,
+            (id >> 48) & 0xffff,
+            id & 0xffff_ffff_ffff
+        ))
+    }
+}
+
+/// ULIDs, 48 bits of milliseconds and 80 random bits that are incremented for further ids
+/// within the same millisecond, see <https://github.com/ulid/spec>.
+pub struct Ulid {
+    rng: Rng,
+    last: u64,
+    random: u128,
+}
+
+impl Ulid {
+    const RANDOM_BITS: u32 = 80;
+
+    pub fn new() -> Self {
+        Self {
+            rng: Rng::from_entropy(),
+            last: 0,
+            random: 0,
+        }
+    }
+
+    fn random(&mut self) -> u128 {
+        (self.rng.next_u64() as u128) << 16 | (self.rng.next_u64() & 0xffff) as u128
+    }
+
+    pub fn next_id(&mut self) -> u128 {
+        let now = unix_millis().max(self.last);
+        if now > self.last {
+            self.last = now;
+            self.random = self.random();
+        } else {
+            self.random += 1;
+            if self.random == 1 << Self::RANDOM_BITS {
+                self.last += 1;
+                self.random = self.random();
+            }
+        }
+        (self.last as u128) << Self::RANDOM_BITS | self.random
+    }
+}
+
+impl Default for Ulid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for Ulid {
+    fn generate(&mut self) -> io::Result<String> {
+        // crockford's base32, 26 characters of 5 bits cover the 128 bits
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        let id = self.next_id();
+        Ok((0..26)
+            .rev()
+            .map(|digit| ALPHABET[(id >> (5 * digit) & 0x1f) as usize] as char)
+            .collect())
+    }
+}
+
+/// Counts up from where the previous run with the same file stopped, so a node that is
+/// restarted never hands out an id twice.
+///
+/// Ids are reserved in blocks that are written to the file before any of them is used,
+/// the rest of the block is skipped after a restart.
+pub struct PersistentCounter {
+    path: PathBuf,
+    node_id: NodeId,
+    next: u64,
+    reserved: u64,
+}
+
+impl PersistentCounter {
+    const BLOCK: u64 = 1000;
+
+    pub fn open(path: impl Into<PathBuf>, node_id: NodeId) -> io::Result<Self> {
+        let path = path.into();
+        let next = match fs::read_to_string(&path) {
+            Ok(reserved) => reserved
+                .trim()
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            node_id,
+            next,
+            reserved: next,
+        })
+    }
+
+    fn reserve(&mut self) -> io::Result<()> {
+        let reserved = self.next + Self::BLOCK;
+        // replace the file as a whole, a crash never leaves a partly written count behind
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        write!(file, "{reserved}")?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+impl IdGenerator for PersistentCounter {
+    fn generate(&mut self) -> io::Result<String> {
+        if self.next == self.reserved {
+            self.reserve()?;
+        }
+        let id = format!("{:020}@{}", self.next, self.node_id);
+        self.next += 1;
+        Ok(id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// [`Snowflake`] with the index of the node among all nodes as its worker.
+    Snowflake,
+    UuidV7,
+    Ulid,
+    /// [`PersistentCounter`] in a file per node in the directory.
+    Counter(PathBuf),
+}
+
+impl Strategy {
+    pub fn generator(
+        &self,
+        node_id: &NodeId,
+        node_ids: &[NodeId],
+    ) -> io::Result<Box<dyn IdGenerator>> {
+        Ok(match self {
+            Self::Snowflake => {
+                let mut node_ids = node_ids.to_vec();
+                node_ids.sort();
+                let worker = node_ids
+                    .iter()
+                    .position(|id| id == node_id)
+                    .expect("node_ids contains the node itself");
+                Box::new(Snowflake::new(worker as u64))
+            }
+            Self::UuidV7 => Box::new(UuidV7::new()),
+            Self::Ulid => Box::new(Ulid::new()),
+            Self::Counter(dir) => {
+                fs::create_dir_all(dir)?;
+                Box::new(PersistentCounter::open(
+                    dir.join(format!("{node_id}.ids")),
+                    node_id.clone(),
+                )?)
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseStrategyError(String);
+
+impl Display for ParseStrategyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown id strategy `{}`, expected one of snowflake, uuid, ulid, counter:<dir>",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseStrategyError {}
+
+impl FromStr for Strategy {
+    type Err = ParseStrategyError;
+
+    /// Parses `snowflake`, `uuid`, `ulid` and `counter:<dir>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "snowflake" => Ok(Self::Snowflake),
+            None if s == "uuid" => Ok(Self::UuidV7),
+            None if s == "ulid" => Ok(Self::Ulid),
+            Some(("counter", dir)) if !dir.is_empty() => Ok(Self::Counter(dir.into())),
+            _ => Err(ParseStrategyError(s.to_owned())),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Client for Maelstrom's key-value services `seq-kv`, `lin-kv` and `lww-kv`.
+
+use std::{marker::PhantomData, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{event_loop::Context, ErrorCode, EventNode, MsgId, NodeError, NodeId, Payload, Result};
+
+#[derive(Debug, Clone)]
+pub enum KvError {
+    KeyDoesNotExist,
+    PreConditionFailed,
+    Timeout,
+    Other(ErrorCode),
+}
+
+impl From<ErrorCode> for KvError {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::KeyDoesNotExist => Self::KeyDoesNotExist,
+            ErrorCode::PreConditionFailed => Self::PreConditionFailed,
+            ErrorCode::Timeout => Self::Timeout,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<KvError> for ErrorCode {
+    fn from(err: KvError) -> Self {
+        match err {
+            KvError::KeyDoesNotExist => Self::KeyDoesNotExist,
+            KvError::PreConditionFailed => Self::PreConditionFailed,
+            KvError::Timeout => Self::Timeout,
+            KvError::Other(code) => code,
+        }
+    }
+}
+
+impl From<KvError> for NodeError {
+    fn from(err: KvError) -> Self {
+        ErrorCode::from(err).into()
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KvRequest<K, V> {
+    Read {
+        key: K,
+    },
+    Write {
+        key: K,
+        value: V,
+    },
+    Cas {
+        key: K,
+        from: V,
+        to: V,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+impl<K, V> Payload for KvRequest<K, V> {}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KvResponse<V> {
+    ReadOk { value: V },
+    WriteOk {},
+    CasOk {},
+}
+
+/// Typed handle for one of the key-value services, keys and values are sent as their json encoding.
+#[derive(Debug, Clone)]
+pub struct Kv<K, V> {
+    service: NodeId,
+    timeout: Duration,
+    types: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V> Kv<K, V>
+where
+    K: Serialize + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+{
+    pub fn new(service: NodeId) -> Self {
+        Self {
+            service,
+            timeout: Duration::from_secs(1),
+            types: PhantomData,
+        }
+    }
+
+    /// Sequentially consistent, reads may be stale.
+    pub fn seq_kv() -> Self {
+        Self::new(NodeId::seq_kv())
+    }
+
+    /// Linearizable.
+    pub fn lin_kv() -> Self {
+        Self::new(NodeId::lin_kv())
+    }
+
+    /// Last write wins, reads may be stale and writes may be lost.
+    pub fn lww_kv() -> Self {
+        Self::new(NodeId::lww_kv())
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn read<N, F>(
+        &self,
+        ctx: &mut Context<N>,
+        msg_id: &mut MsgId,
+        key: K,
+        callback: F,
+    ) -> Result<MsgId>
+    where
+        N: EventNode,
+        F: FnOnce(&mut N, &mut Context<N>, Result<V, KvError>) -> Result<()> + 'static,
+    {
+        ctx.rpc(
+            self.service.clone(),
+            msg_id,
+            KvRequest::<K, V>::Read { key },
+            self.timeout,
+            move |node, ctx, result: Result<KvResponse<V>, ErrorCode>| {
+                let result = match result {
+                    Ok(KvResponse::ReadOk { value }) => Ok(value),
+                    Ok(_) => Err(KvError::Other(ErrorCode::MalformedRequest)),
+                    Err(code) => Err(code.into()),
+                };
+                callback(node, ctx, result)
+            },
+        )
+    }
+
+    pub fn write<N, F>(
+        &self,
+        ctx: &mut Context<N>,
+        msg_id: &mut MsgId,
+        key: K,
+        value: V,
+        callback: F,
+    ) -> Result<MsgId>
+    where
+        N: EventNode,
+        F: FnOnce(&mut N, &mut Context<N>, Result<(), KvError>) -> Result<()> + 'static,
+    {
+        ctx.rpc(
+            self.service.clone(),
+            msg_id,
+            KvRequest::Write { key, value },
+            self.timeout,
+            move |node, ctx, result: Result<KvResponse<V>, ErrorCode>| {
+                let result = match result {
+                    Ok(KvResponse::WriteOk {}) => Ok(()),
+                    Ok(_) => Err(KvError::Other(ErrorCode::MalformedRequest)),
+                    Err(code) => Err(code.into()),
+                };
+                callback(node, ctx, result)
+            },
+        )
+    }
+
+    /// Replace the value of `key` with `to` if it currently is `from`,
+    /// with `create_if_not_exists` a missing key is created with `to`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cas<N, F>(
+        &self,
+        ctx: &mut Context<N>,
+        msg_id: &mut MsgId,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        callback: F,
+    ) -> Result<MsgId>
+    where
+        N: EventNode,
+        F: FnOnce(&mut N, &mut Context<N>, Result<(), KvError>) -> Result<()> + 'static,
+    {
+        ctx.rpc(
+            self.service.clone(),
+            msg_id,
+            KvRequest::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            },
+            self.timeout,
+            move |node, ctx, result: Result<KvResponse<V>, ErrorCode>| {
+                let result = match result {
+                    Ok(KvResponse::CasOk {}) => Ok(()),
+                    Ok(_) => Err(KvError::Other(ErrorCode::MalformedRequest)),
+                    Err(code) => Err(code.into()),
+                };
+                callback(node, ctx, result)
+            },
+        )
+    }
+}
@@ -1,10 +1,28 @@
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
-    io::Write,
     io::{stdin, stdout},
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex, PoisonError},
 };
 
+pub mod batch;
+pub mod clock;
+pub mod crdt;
+mod error;
+pub mod event_loop;
+pub mod gossip;
+pub mod handler;
+pub mod ids;
+pub mod kv;
+mod rng;
+pub mod sim;
+pub mod topology;
+
+use clock::Clock;
+pub use error::{NodeError, Result};
+use event_loop::{Context, Event};
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 #[serde(transparent)]
@@ -29,6 +47,14 @@ impl NodeId {
     pub fn seq_kv() -> Self {
         Self(String::from("seq-kv"))
     }
+
+    pub fn lin_kv() -> Self {
+        Self(String::from("lin-kv"))
+    }
+
+    pub fn lww_kv() -> Self {
+        Self(String::from("lww-kv"))
+    }
 }
 
 impl Display for NodeId {
@@ -47,6 +73,78 @@ pub struct Message<P> {
 
 pub trait Payload {}
 
+/// A request payload together with the payload of its replies, see [`macro@Payload`].
+pub trait Request: Payload {
+    type Reply: Payload;
+}
+
+/// The variant names are the message types of the protocol in snake case, so the replies
+/// usually all end in `Ok` and need `#[allow(clippy::enum_variant_names)]`. A message that
+/// nodes send each other, like gossip, is listed with the requests to be received and with
+/// the replies to be sent.
+///
+/// Payloads the derive accepts, each request has a reply and borrowed fields are fine:
+///
+/// ```
+/// use std::borrow::Cow;
+///
+/// use dist_sys_challenge::Payload;
+///
+/// #[derive(Payload)]
+/// enum Replies {
+///     EchoOk { echo: String },
+/// }
+///
+/// #[derive(Payload)]
+/// #[payload(reply = Replies)]
+/// enum Requests<'a> {
+///     Echo {
+///         #[serde(borrow)]
+///         echo: Cow<'a, str>,
+///     },
+/// }
+/// ```
+///
+/// A request without a reply is rejected, here `Read` has no `ReadOk`:
+///
+/// ```compile_fail
+/// # use dist_sys_challenge::Payload;
+/// #[derive(Payload)]
+/// enum Replies {
+///     EchoOk { echo: String },
+/// }
+///
+/// #[derive(Payload)]
+/// #[payload(reply = Replies)]
+/// enum Requests {
+///     Echo { echo: String },
+///     Read {},
+/// }
+/// ```
+///
+/// So are type parameters:
+///
+/// ```compile_fail
+/// # use dist_sys_challenge::Payload;
+/// #[derive(Payload)]
+/// enum Replies<T> {
+///     ReadOk { value: T },
+/// }
+/// ```
+///
+/// And tuple variants, they have no field names to put in the body:
+///
+/// ```compile_fail
+/// # use dist_sys_challenge::Payload;
+/// #[derive(Payload)]
+/// enum Replies {
+///     ReadOk(usize),
+/// }
+/// ```
+pub use dist_sys_challenge_derive::Payload;
+
+impl Payload for serde_json::Value {}
+
 impl<P: Payload> Message<P> {
     pub fn new(src: NodeId, dst: NodeId, msg_id: Option<&mut MsgId>, payload: P) -> Self {
         Message {
@@ -59,6 +157,7 @@ impl<P: Payload> Message<P> {
                     mid
                 }),
                 in_reply_to: None,
+                clock: None,
                 payload,
             },
         }
@@ -70,11 +169,6 @@ impl<P: Payload> Message<P> {
         code: ErrorCode,
         text: Option<String>,
     ) -> std::io::Result<()> {
-        let code = match code {
-            ErrorCode::Custom { code } => code,
-            standard => standard.discriminant() as usize,
-        };
-
         self.respond(writer, None, Error::Error { code, text })
     }
 
@@ -88,6 +182,11 @@ impl<P: Payload> Message<P> {
         R: Payload,
         Message<R>: Serialize,
     {
+        self.reply(msg_id, payload).send(writer)
+    }
+
+    /// The response to this message, without sending it.
+    pub fn reply<R: Payload>(&self, msg_id: Option<&mut MsgId>, payload: R) -> Message<R> {
         Message {
             src: self.dst.clone(),
             dst: self.src.clone(),
@@ -98,16 +197,30 @@ impl<P: Payload> Message<P> {
                     mid
                 }),
                 in_reply_to: self.body.msg_id,
+                clock: None,
                 payload,
             },
         }
-        .send(writer)
+    }
+
+    /// The logical time the sender stamped the message with, see [`clock`].
+    pub fn clock(&self) -> Option<&Clock> {
+        self.body.clock.as_ref()
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.body.clock = Some(clock);
+        self
     }
 
     pub fn src(&self) -> &NodeId {
         &self.src
     }
 
+    pub fn dst(&self) -> &NodeId {
+        &self.dst
+    }
+
     pub fn id(&self) -> Option<MsgId> {
         self.body.msg_id
     }
@@ -120,8 +233,10 @@ impl<P: Payload> Message<P> {
     where
         Self: Serialize,
     {
-        serde_json::to_writer(&mut *writer, &self)?;
-        writer.write_all(b"\n")
+        // the whole line goes out in one write, see `Output`
+        let mut line = serde_json::to_vec(&self)?;
+        line.push(b'\n');
+        writer.write_all(&line)
     }
 
     pub fn payload(&self) -> &P {
@@ -133,6 +248,9 @@ impl<P: Payload> Message<P> {
 struct Body<P> {
     msg_id: Option<MsgId>,
     in_reply_to: Option<MsgId>,
+    // next to the flattened payload, so named to stay clear of payload fields
+    #[serde(rename = "_clock", default, skip_serializing_if = "Option::is_none")]
+    clock: Option<Clock>,
     #[serde(flatten)]
     payload: P,
 }
@@ -159,67 +277,102 @@ impl Payload for InitOk {}
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Error {
-    Error { code: usize, text: Option<String> },
+    Error {
+        code: ErrorCode,
+        text: Option<String>,
+    },
 }
 
 impl Payload for Error {}
 
+/// Maelstrom's error codes, see
+/// <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
 #[non_exhaustive]
-#[repr(u16)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ErrorCode {
-    Timeout = 0,
-    NodeNotFound = 1,
-    NotSupported = 10,
-    TemporarilyUnavailable = 11,
-    MalformedRequest = 12,
-    Chrash = 13,
-    Abort = 14,
-    KeyDoesNotExist = 20,
-    KeyExistsAlready = 21,
-    PreConditionFailed = 22,
-    TransactionConflict = 30,
-
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyExistsAlready,
+    PreConditionFailed,
+    TransactionConflict,
+
+    // user defined errors, see `ErrorCode::custom`
     #[non_exhaustive]
     Custom {
         code: usize,
-    }, // userdefined error should be >= 100
+    },
 }
 
-impl<'de> Deserialize<'de> for ErrorCode {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let val = usize::deserialize(deserializer)?;
-        Ok(match val {
+impl ErrorCode {
+    /// User defined error, maelstrom reserves the codes below 1000.
+    pub fn custom(code: usize) -> Self {
+        assert!(code >= 1000, "custom error codes have to be >= 1000");
+        Self::Custom { code }
+    }
+
+    pub fn from_code(code: usize) -> Self {
+        match code {
             0 => Self::Timeout,
             1 => Self::NodeNotFound,
             10 => Self::NotSupported,
             11 => Self::TemporarilyUnavailable,
             12 => Self::MalformedRequest,
-            13 => Self::Chrash,
+            13 => Self::Crash,
             14 => Self::Abort,
             20 => Self::KeyDoesNotExist,
             21 => Self::KeyExistsAlready,
             22 => Self::PreConditionFailed,
             30 => Self::TransactionConflict,
-            _ => Self::custom(val),
-        })
+            // codes we don't know are kept as they are, even reserved ones
+            code => Self::Custom { code },
+        }
+    }
+
+    pub fn code(&self) -> usize {
+        match self {
+            Self::Timeout => 0,
+            Self::NodeNotFound => 1,
+            Self::NotSupported => 10,
+            Self::TemporarilyUnavailable => 11,
+            Self::MalformedRequest => 12,
+            Self::Crash => 13,
+            Self::Abort => 14,
+            Self::KeyDoesNotExist => 20,
+            Self::KeyExistsAlready => 21,
+            Self::PreConditionFailed => 22,
+            Self::TransactionConflict => 30,
+            Self::Custom { code } => *code,
+        }
+    }
+
+    /// Whether the operation certainly did not take effect, after an indefinite error
+    /// (a timeout, a crash or an unknown code) it may or may not have happened.
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, Self::Timeout | Self::Crash | Self::Custom { .. })
     }
 }
 
-impl ErrorCode {
-    pub fn custom(code: usize) -> Self {
-        assert!(code >= 1000);
-        Self::Custom { code }
+impl Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.code().serialize(serializer)
     }
+}
 
-    fn discriminant(&self) -> u16 {
-        // SAFETY: Because `Self` is marked `repr(u16)`, its layout is a `repr(C)` `union`
-        // between `repr(C)` structs, each of which has the `u16` discriminant as its first
-        // field, so we can read the discriminant without offsetting the pointer.
-        unsafe { *<*const _>::from(self).cast::<u16>() }
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        usize::deserialize(deserializer).map(Self::from_code)
     }
 }
 
@@ -228,16 +381,114 @@ struct EmptyBody {}
 
 impl Payload for EmptyBody {}
 
-pub trait Node {
-    type Msg: serde::de::DeserializeOwned + Payload;
+/// Shared sink for outgoing messages, cheap to clone so it can be handed to worker threads.
+///
+/// Every `write` is passed on as a whole while holding the lock, so a line written with a
+/// single call (as [`Message::send`] does) never interleaves with lines of other threads.
+/// The sink from [`Output::stdout`] is buffered, what was written only goes out on `flush`,
+/// both runtimes flush once they handled the input that is available.
+#[derive(Clone)]
+pub struct Output(Arc<Mutex<dyn Write + Send>>);
+
+impl Output {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(BufWriter::with_capacity(64 * 1024, stdout()))
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush()
+    }
+}
+
+/// A node that handles every message on its own, without timers or rpcs. It runs on [`run`],
+/// and through the impl below on [`event_loop::run`] and the simulator as well.
+pub trait Node: Sized {
+    /// Messages are parsed from the line they arrived on and may borrow from it, e.g.
+    /// `Echo { #[serde(borrow)] echo: Cow<'a, str> }`, a `&'a str` would fail on strings with
+    /// escapes. The line is reused once the message was handled.
+    type Msg<'a>: Deserialize<'a> + Payload;
+
+    fn new(init: Init, output: Output) -> Self;
+
+    /// An `Err` is turned into the reply to `request`, see [`NodeError`].
+    fn process(&mut self, request: &Message<Self::Msg<'_>>) -> Result<()>;
+}
+
+/// A node for [`event_loop::run`] and the simulator, which can set timers and send rpcs
+/// through its [`Context`]. Nodes that need neither implement [`Node`] instead.
+pub trait EventNode: Sized {
+    /// See [`Node::Msg`].
+    type Msg<'a>: Deserialize<'a> + Payload;
+
+    fn new(init: Init, output: Output) -> Self;
+
+    fn start(&mut self, _ctx: &mut Context<Self>) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, ctx: &mut Context<Self>, event: &Event<Self::Msg<'_>>) -> Result<()> {
+        match event {
+            Event::Message(msg) | Event::Reply(msg) => self.on_message(ctx, msg),
+            Event::Timer(name) => self.on_tick(ctx, name),
+        }
+    }
+
+    /// Called for messages and for replies no rpc callback took,
+    /// an `Err` is turned into the reply to `request`, see [`NodeError`].
+    fn on_message(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> Result<()>;
+
+    // Called for timers registered with `Context::set_timer`, there is no request to answer
+    // so an `Err` is only logged, like one returned from an rpc callback
+    fn on_tick(&mut self, _ctx: &mut Context<Self>, _timer: &str) -> Result<()> {
+        Ok(())
+    }
+
+    // Called once stdin is closed, the last chance to send messages before the process exits
+    fn shutdown(&mut self, _ctx: &mut Context<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<P: Node> EventNode for P {
+    type Msg<'a> = P::Msg<'a>;
 
-    fn new(init: Init) -> Self;
+    fn new(init: Init, output: Output) -> Self {
+        P::new(init, output)
+    }
 
-    fn process(&mut self, request: &Message<Self::Msg>) -> std::io::Result<()>;
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> Result<()> {
+        self.process(request)
+    }
 }
 
-pub fn run<N: Node>() -> std::io::Result<()> {
+pub fn run<N: Node>() -> Result<()> {
     let stdin = stdin();
+    let mut output = Output::stdout();
 
     let init: Message<Init> = {
         let mut init = String::new();
@@ -245,33 +496,90 @@ pub fn run<N: Node>() -> std::io::Result<()> {
         serde_json::from_str(&init)?
     };
 
-    init.respond(&mut stdout(), Some(&mut MsgId(0)), InitOk::InitOk {})?;
+    init.respond(&mut output, Some(&mut MsgId(0)), InitOk::InitOk {})?;
+    output.flush()?;
 
-    let mut node = N::new(init.body.payload);
+    let mut node = N::new(init.body.payload, output.clone());
 
-    let mut line;
+    let mut line = String::new();
 
     loop {
-        line = String::new();
-        stdin.read_line(&mut line)?;
-        let msg = serde_json::from_str::<Message<_>>(&line);
-        match msg {
-            Ok(msg) => {
-                if let Err(err) = node.process(&msg) {
-                    msg.respond_error(&mut stdout(), ErrorCode::Chrash, Some(err.to_string()))?;
-                }
-            }
-            Err(err) => {
-                if let Ok(fb_msg) = serde_json::from_str::<Message<EmptyBody>>(&line) {
-                    fb_msg.respond_error(
-                        &mut stdout(),
-                        ErrorCode::MalformedRequest,
-                        Some(format!("{err}")),
-                    )?;
-                } else {
-                    return Err(err.into());
-                }
+        line.clear();
+        // maelstrom closes stdin at the end of a run
+        if stdin.read_line(&mut line)? == 0 {
+            output.flush()?;
+            return Ok(());
+        }
+        dispatch(&mut node, &line, &mut output)?;
+        output.flush()?;
+    }
+}
+
+fn dispatch<N: Node>(node: &mut N, line: &str, output: &mut Output) -> Result<()> {
+    if let Some(msg) = parse::<N::Msg<'_>>(line, output)? {
+        if let Err(err) = node.process(&msg) {
+            error::report(Some(&msg), err, output)?;
+        }
+    }
+    Ok(())
+}
+
+// Malformed requests are answered with an error, only lines that aren't messages at all are fatal
+fn parse<'a, M: Deserialize<'a>>(line: &'a str, output: &mut Output) -> Result<Option<Message<M>>> {
+    match serde_json::from_str::<Message<M>>(line) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(err) => {
+            if let Ok(fb_msg) = serde_json::from_str::<Message<EmptyBody>>(line) {
+                fb_msg.respond_error(
+                    output,
+                    ErrorCode::MalformedRequest,
+                    Some(format!("{err}")),
+                )?;
+                Ok(None)
+            } else {
+                Err(err.into())
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes() {
+        // code, error, definite
+        let table = [
+            (0, ErrorCode::Timeout, false),
+            (1, ErrorCode::NodeNotFound, true),
+            (10, ErrorCode::NotSupported, true),
+            (11, ErrorCode::TemporarilyUnavailable, true),
+            (12, ErrorCode::MalformedRequest, true),
+            (13, ErrorCode::Crash, false),
+            (14, ErrorCode::Abort, true),
+            (20, ErrorCode::KeyDoesNotExist, true),
+            (21, ErrorCode::KeyExistsAlready, true),
+            (22, ErrorCode::PreConditionFailed, true),
+            (30, ErrorCode::TransactionConflict, true),
+            (1000, ErrorCode::custom(1000), false),
+            // reserved but unknown codes are kept
+            (2, ErrorCode::Custom { code: 2 }, false),
+        ];
+        for (code, error, definite) in table {
+            assert_eq!(ErrorCode::from_code(code), error);
+            assert_eq!(error.code(), code);
+            assert_eq!(error.is_definite(), definite, "{error:?}");
+
+            let json = serde_json::to_string(&error).unwrap();
+            assert_eq!(json, code.to_string());
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), error);
+        }
+    }
+
+    #[test]
+    #[should_panic = "custom error codes have to be >= 1000"]
+    fn reserved_custom_code() {
+        ErrorCode::custom(999);
     }
 }
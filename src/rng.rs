@@ -0,0 +1,40 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+// SplitMix64, good enough for latencies and jitter and small enough to not need a dependency
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn from_entropy() -> Self {
+        Self(RandomState::new().build_hasher().finish())
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+
+    pub(crate) fn duration(&mut self, range: &RangeInclusive<Duration>) -> Duration {
+        let (start, end) = (range.start().as_nanos(), range.end().as_nanos());
+        if end <= start {
+            return *range.start();
+        }
+        let span = (end - start + 1) as u64;
+        *range.start() + Duration::from_nanos(self.next_u64() % span)
+    }
+}
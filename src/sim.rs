@@ -0,0 +1,381 @@
+//! In-process network for exercising [`EventNode`] implementations without Maelstrom.
+//!
+//! Nodes are driven synchronously on the calling thread, messages between them are
+//! delivered in virtual time with a latency drawn from a seeded generator, so a run
+//! with the same seed and the same nodes always delivers in the same order.
+//! Timers and rpc timeouts fire in virtual time as well, so a run never sleeps.
+
+pub mod services;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    event_loop::Driver, rng::Rng, Body, EventNode, Init, Message, MsgId, NodeId, Output, Result,
+};
+
+type Filter = Box<dyn FnMut(&Message<Value>) -> bool>;
+
+pub struct Network {
+    rng: Rng,
+    now: Duration,
+    latency: RangeInclusive<Duration>,
+    in_flight: BTreeMap<(Duration, u64), (NodeId, String)>,
+    sent: u64,
+    nodes: BTreeMap<NodeId, Host>,
+    client: NodeId,
+    client_msg_id: MsgId,
+    replies: HashMap<MsgId, Message<Value>>,
+    // group of each partitioned node, messages between groups are dropped
+    partitions: HashMap<NodeId, usize>,
+    drop_if: Option<Filter>,
+    dropped: u64,
+}
+
+struct Host {
+    node: Box<dyn Deliver>,
+    outbox: Outbox,
+}
+
+trait Deliver {
+    fn deliver(&mut self, now: Duration, line: &str) -> Result<()>;
+
+    fn next_deadline(&self) -> Option<Duration>;
+
+    fn fire(&mut self, now: Duration) -> Result<()>;
+
+    fn shutdown(&mut self, now: Duration) -> Result<()>;
+}
+
+impl<N: EventNode> Deliver for Driver<N> {
+    fn deliver(&mut self, now: Duration, line: &str) -> Result<()> {
+        Driver::deliver(self, now, line)
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        Driver::next_deadline(self)
+    }
+
+    fn fire(&mut self, now: Duration) -> Result<()> {
+        Driver::fire(self, now)
+    }
+
+    fn shutdown(&mut self, now: Duration) -> Result<()> {
+        Driver::shutdown(self, now)
+    }
+}
+
+enum Next {
+    Delivery,
+    Timer(NodeId),
+}
+
+#[derive(Clone, Default)]
+struct Outbox(Arc<Mutex<Vec<u8>>>);
+
+impl Outbox {
+    fn take_lines(&self) -> Vec<String> {
+        let mut buf = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(end) = buf.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let complete = buf.drain(..=end).collect::<Vec<_>>();
+        String::from_utf8(complete)
+            .expect("nodes should only write utf-8")
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            now: Duration::ZERO,
+            latency: Duration::ZERO..=Duration::from_millis(5),
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            nodes: BTreeMap::new(),
+            client: NodeId(String::from("c1")),
+            client_msg_id: MsgId::ONE,
+            replies: HashMap::new(),
+            partitions: HashMap::new(),
+            drop_if: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn with_latency(mut self, latency: RangeInclusive<Duration>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Start `count` nodes named `n0`, `n1`, ... that all know about each other.
+    pub fn spawn<N: EventNode + 'static>(&mut self, count: usize) -> Vec<NodeId> {
+        self.spawn_with(count, N::new)
+    }
+
+    /// Like [`Network::spawn`], the nodes are created by `new`, see [`crate::event_loop::run_with`].
+    pub fn spawn_with<N: EventNode + 'static>(
+        &mut self,
+        count: usize,
+        new: impl Fn(Init, Output) -> N,
+    ) -> Vec<NodeId> {
+        let node_ids = (0..count)
+            .map(|i| NodeId(format!("n{i}")))
+            .collect::<Vec<_>>();
+        for node_id in &node_ids {
+            self.mount(node_id.clone(), node_ids.clone(), &new);
+        }
+        node_ids
+    }
+
+    /// Start a single node under a fixed name, e.g. a stand-in for a Maelstrom service.
+    pub fn add_service<N: EventNode + 'static>(&mut self, name: &str) -> NodeId {
+        let node_id = NodeId(name.to_owned());
+        self.mount(node_id.clone(), Vec::new(), N::new);
+        node_id
+    }
+
+    fn mount<N: EventNode + 'static>(
+        &mut self,
+        node_id: NodeId,
+        node_ids: Vec<NodeId>,
+        new: impl FnOnce(Init, Output) -> N,
+    ) {
+        let outbox = Outbox::default();
+        let mut driver = Driver::new(
+            new,
+            Init::Init {
+                node_id: node_id.clone(),
+                node_ids,
+            },
+            Output::new(outbox.clone()),
+            Rng::new(self.rng.next_u64()),
+        );
+        if let Err(err) = driver.start(self.now) {
+            panic!("{node_id} failed to start: {err}");
+        }
+        let host = Host {
+            node: Box::new(driver),
+            outbox,
+        };
+        assert!(
+            self.nodes.insert(node_id.clone(), host).is_none(),
+            "node {node_id} already exists"
+        );
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Number of messages sent so far by the nodes and the client, dropped ones included.
+    pub fn messages_sent(&self) -> u64 {
+        self.sent + self.dropped
+    }
+
+    /// Split the nodes into `groups` that can't reach each other until [`Network::heal`],
+    /// nodes not in any group and the client still reach everyone.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.partitions = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, nodes)| nodes.iter().map(move |node| (node.clone(), group)))
+            .collect();
+    }
+
+    /// Drop the messages between nodes that `filter` returns true for, e.g. to lose a reply
+    /// after its request went through, until [`Network::heal`].
+    pub fn drop_if(&mut self, filter: impl FnMut(&Message<Value>) -> bool + 'static) {
+        self.drop_if = Some(Box::new(filter));
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+        self.drop_if = None;
+    }
+
+    /// Shut `node` down as if its stdin was closed and remove it from the network,
+    /// what it sends while shutting down is still delivered.
+    pub fn stop(&mut self, node: &NodeId) {
+        let mut host = self.nodes.remove(node).expect("stopped node exists");
+        if let Err(err) = host.node.shutdown(self.now) {
+            panic!("{node} failed to shut down: {err}");
+        }
+        for line in host.outbox.take_lines() {
+            self.route(line);
+        }
+    }
+
+    /// Send a request from the client to `dest`, the payload should serialize to a maelstrom body.
+    pub fn send<P: Serialize>(&mut self, dest: &NodeId, payload: P) -> MsgId {
+        let msg_id = self.client_msg_id;
+        self.client_msg_id = MsgId(msg_id.0 + 1);
+        let msg = Message {
+            src: self.client.clone(),
+            dst: dest.clone(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                clock: None,
+                payload: serde_json::to_value(payload).expect("payload should serialize"),
+            },
+        };
+        let line = serde_json::to_string(&msg).expect("message should serialize");
+        self.enqueue(dest.clone(), line);
+        msg_id
+    }
+
+    /// The reply the client received for the request with `msg_id`, if it arrived yet.
+    pub fn reply(&self, msg_id: MsgId) -> Option<&Message<Value>> {
+        self.replies.get(&msg_id)
+    }
+
+    /// Send a request and run the network until its reply arrives,
+    /// panics if none arrived within a minute of virtual time.
+    pub fn call<P: Serialize>(&mut self, dest: &NodeId, payload: P) -> Message<Value> {
+        let msg_id = self.send(dest, payload);
+        let deadline = self.now + Duration::from_secs(60);
+        loop {
+            if let Some(reply) = self.replies.remove(&msg_id) {
+                return reply;
+            }
+            assert!(
+                self.now < deadline && self.step(),
+                "no reply from {dest} for request {msg_id}"
+            );
+        }
+    }
+
+    /// Deliver everything that is due within the next `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.next().is_some_and(|(at, _)| at <= until) {
+            self.step();
+        }
+        self.now = until;
+    }
+
+    /// Deliver messages until none are in flight, timers keep firing in between.
+    pub fn run_until_idle(&mut self) {
+        while !self.in_flight.is_empty() {
+            self.step();
+        }
+    }
+
+    fn next(&self) -> Option<(Duration, Next)> {
+        let delivery = self
+            .in_flight
+            .first_key_value()
+            .map(|(&(at, _), _)| (at, Next::Delivery));
+        let timer = self
+            .nodes
+            .iter()
+            .filter_map(|(node_id, host)| Some((host.node.next_deadline()?, node_id)))
+            .min()
+            .map(|(at, node_id)| (at, Next::Timer(node_id.clone())));
+        match (delivery, timer) {
+            (Some(delivery), Some(timer)) if timer.0 < delivery.0 => Some(timer),
+            (Some(delivery), _) => Some(delivery),
+            (None, timer) => timer,
+        }
+    }
+
+    fn step(&mut self) -> bool {
+        let Some((at, next)) = self.next() else {
+            return false;
+        };
+        self.now = self.now.max(at);
+
+        let (dest, line) = match next {
+            Next::Delivery => {
+                let (_, delivery) = self.in_flight.pop_first().expect("delivery is due");
+                delivery
+            }
+            Next::Timer(node_id) => {
+                let host = self.nodes.get_mut(&node_id).expect("timer of a known node");
+                if let Err(err) = host.node.fire(self.now) {
+                    panic!("{node_id} failed to handle a timer: {err}");
+                }
+                self.collect();
+                return true;
+            }
+        };
+
+        if dest == self.client {
+            let reply: Message<Value> =
+                serde_json::from_str(&line).expect("replies should be valid messages");
+            if let Some(in_reply_to) = reply.in_response_to() {
+                self.replies.insert(in_reply_to, reply);
+            }
+        } else if let Some(host) = self.nodes.get_mut(&dest) {
+            if let Err(err) = host.node.deliver(self.now, &line) {
+                panic!("{dest} failed to process {line}: {err}");
+            }
+        }
+
+        self.collect();
+        true
+    }
+
+    fn collect(&mut self) {
+        let lines = self
+            .nodes
+            .values()
+            .flat_map(|host| host.outbox.take_lines())
+            .collect::<Vec<_>>();
+        for line in lines {
+            self.route(line);
+        }
+    }
+
+    // enqueue a line sent by a node unless a partition or `drop_if` is in the way
+    fn route(&mut self, line: String) {
+        let msg: Message<Value> =
+            serde_json::from_str(&line).expect("nodes should only send valid messages");
+        if let (Some(from), Some(to)) =
+            (self.partitions.get(&msg.src), self.partitions.get(&msg.dst))
+        {
+            if from != to {
+                self.dropped += 1;
+                return;
+            }
+        }
+        if self.drop_if.as_mut().is_some_and(|filter| filter(&msg)) {
+            self.dropped += 1;
+            return;
+        }
+        self.enqueue(msg.dst, line);
+    }
+
+    fn enqueue(&mut self, dest: NodeId, line: String) {
+        let latency = self.rng.duration(&self.latency);
+        self.sent += 1;
+        self.in_flight
+            .insert((self.now + latency, self.sent), (dest, line));
+    }
+}
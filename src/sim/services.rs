@@ -0,0 +1,361 @@
+//! Stand-ins for Maelstrom's key-value services, mount them with [`Network::add_service`]
+//! under the name the node expects, e.g. `net.add_service::<SeqKv>("seq-kv")`.
+//!
+//! [`Network::add_service`]: super::Network::add_service
+
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    event_loop::{Context, Timer},
+    rng::Rng,
+    ErrorCode, EventNode, Init, Message, MsgId, Node, NodeId, Output, Payload, Result,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KvMessages {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+impl Payload for KvMessages {}
+
+impl KvMessages {
+    // keys can be any json value, their encoding is used to tell them apart
+    fn key(&self) -> String {
+        match self {
+            Self::Read { key } | Self::Write { key, .. } | Self::Cas { key, .. } => key.to_string(),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KvReply {
+    ReadOk { value: Value },
+    WriteOk {},
+    CasOk {},
+}
+
+impl Payload for KvReply {}
+
+type KvResult = Result<KvReply, (ErrorCode, String)>;
+
+/// Apply `op` to the `current` value of its key, returns the new value if it changed.
+fn apply(op: &KvMessages, current: Option<&Value>) -> (KvResult, Option<Value>) {
+    match (op, current) {
+        (KvMessages::Read { .. }, Some(value)) => (
+            Ok(KvReply::ReadOk {
+                value: value.clone(),
+            }),
+            None,
+        ),
+        (KvMessages::Write { value, .. }, _) => (Ok(KvReply::WriteOk {}), Some(value.clone())),
+        (KvMessages::Cas { from, to, .. }, Some(value)) if value == from => {
+            (Ok(KvReply::CasOk {}), Some(to.clone()))
+        }
+        (KvMessages::Cas { from, .. }, Some(value)) => (
+            Err((
+                ErrorCode::PreConditionFailed,
+                format!("current value {value} is not {from}"),
+            )),
+            None,
+        ),
+        (
+            KvMessages::Cas {
+                to,
+                create_if_not_exists: true,
+                ..
+            },
+            None,
+        ) => (Ok(KvReply::CasOk {}), Some(to.clone())),
+        (KvMessages::Read { .. } | KvMessages::Cas { .. }, None) => (
+            Err((
+                ErrorCode::KeyDoesNotExist,
+                format!("key {} does not exist", op.key()),
+            )),
+            None,
+        ),
+    }
+}
+
+fn respond(
+    output: &mut Output,
+    msg_seq_id: &mut MsgId,
+    request: &Message<KvMessages>,
+    result: KvResult,
+) -> Result<()> {
+    match result {
+        Ok(reply) => request.respond(output, Some(msg_seq_id), reply)?,
+        Err((code, text)) => request.respond_error(output, code, Some(text))?,
+    }
+    Ok(())
+}
+
+fn seeded(init: &Init) -> Rng {
+    let Init::Init { node_id, .. } = init;
+    Rng::new(node_id.to_string().bytes().fold(0, |seed: u64, byte| {
+        seed.wrapping_mul(31).wrapping_add(u64::from(byte))
+    }))
+}
+
+/// Linearizable, every operation sees the effects of all operations before it.
+pub struct LinKv {
+    values: HashMap<String, Value>,
+    msg_seq_id: MsgId,
+    output: Output,
+}
+
+impl Node for LinKv {
+    type Msg<'a> = KvMessages;
+
+    fn new(_: Init, output: Output) -> Self {
+        Self {
+            values: HashMap::new(),
+            msg_seq_id: MsgId::ONE,
+            output,
+        }
+    }
+
+    fn process(&mut self, request: &Message<Self::Msg<'_>>) -> Result<()> {
+        let key = request.payload().key();
+        let (result, new) = apply(request.payload(), self.values.get(&key));
+        if let Some(new) = new {
+            self.values.insert(key, new);
+        }
+        respond(&mut self.output, &mut self.msg_seq_id, request, result)
+    }
+}
+
+/// Sequentially consistent, writes are applied in a single order but a read may
+/// return any state at least as new as the last one its client has observed.
+pub struct SeqKv {
+    // version at which each value of a key was written, oldest first
+    history: HashMap<String, Vec<(usize, Value)>>,
+    latest: usize,
+    observed: HashMap<NodeId, usize>,
+    rng: Rng,
+    msg_seq_id: MsgId,
+    output: Output,
+}
+
+impl Node for SeqKv {
+    type Msg<'a> = KvMessages;
+
+    fn new(init: Init, output: Output) -> Self {
+        Self {
+            history: HashMap::new(),
+            latest: 0,
+            observed: HashMap::new(),
+            rng: seeded(&init),
+            msg_seq_id: MsgId::ONE,
+            output,
+        }
+    }
+
+    fn process(&mut self, request: &Message<Self::Msg<'_>>) -> Result<()> {
+        let key = request.payload().key();
+        let observed = self.observed.entry(request.src().clone()).or_default();
+        let history = self.history.entry(key).or_default();
+
+        let version = match request.payload() {
+            KvMessages::Read { .. } => *observed + self.rng.index(self.latest - *observed + 1),
+            // updates are checked against the latest state
+            KvMessages::Write { .. } | KvMessages::Cas { .. } => self.latest,
+        };
+        let current = history
+            .iter()
+            .rev()
+            .find(|(written, _)| *written <= version)
+            .map(|(_, value)| value);
+
+        let (result, new) = apply(request.payload(), current);
+        *observed = version;
+        if let Some(new) = new {
+            self.latest += 1;
+            history.push((self.latest, new));
+            *observed = self.latest;
+        }
+        respond(&mut self.output, &mut self.msg_seq_id, request, result)
+    }
+}
+
+/// Eventually consistent, each request is handled by one of several replicas
+/// which only periodically exchange their values, the write with the newest
+/// timestamp wins so reads may be stale and concurrent writes get lost.
+pub struct LwwKv {
+    replicas: Vec<HashMap<String, (u64, Value)>>,
+    clock: u64,
+    rng: Rng,
+    msg_seq_id: MsgId,
+    output: Output,
+}
+
+impl LwwKv {
+    const REPLICAS: usize = 3;
+    const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+}
+
+impl EventNode for LwwKv {
+    type Msg<'a> = KvMessages;
+
+    fn new(init: Init, output: Output) -> Self {
+        Self {
+            replicas: vec![HashMap::new(); Self::REPLICAS],
+            clock: 0,
+            rng: seeded(&init),
+            msg_seq_id: MsgId::ONE,
+            output,
+        }
+    }
+
+    fn start(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.set_timer(Timer::every("sync", Self::SYNC_INTERVAL));
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        request: &Message<Self::Msg<'_>>,
+    ) -> Result<()> {
+        let key = request.payload().key();
+        let replica = &mut self.replicas[self.rng.index(Self::REPLICAS)];
+
+        let (result, new) = apply(request.payload(), replica.get(&key).map(|(_, value)| value));
+        if let Some(new) = new {
+            self.clock += 1;
+            replica.insert(key, (self.clock, new));
+        }
+        respond(&mut self.output, &mut self.msg_seq_id, request, result)
+    }
+
+    fn on_tick(&mut self, _ctx: &mut Context<Self>, _timer: &str) -> Result<()> {
+        let mut merged = HashMap::<String, (u64, Value)>::new();
+        for (key, (written, value)) in self.replicas.iter().flatten() {
+            if merged.get(key).is_none_or(|(newest, _)| newest < written) {
+                merged.insert(key.clone(), (*written, value.clone()));
+            }
+        }
+        self.replicas.fill(merged);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::sim::{Network, Outbox};
+
+    fn code(reply: &Value) -> ErrorCode {
+        assert_eq!(reply["type"], "error", "expected an error, got {reply}");
+        serde_json::from_value(reply["code"].clone()).unwrap()
+    }
+
+    // Hands `body` from `src` straight to `kv`, for tests that need more than one client
+    fn call<S>(kv: &mut S, outbox: &Outbox, src: &str, body: Value) -> Value
+    where
+        S: for<'a> Node<Msg<'a> = KvMessages>,
+    {
+        let request = serde_json::from_value(json!({"src": src, "dest": "kv", "body": body}))
+            .expect("request should be a kv message");
+        kv.process(&request).unwrap();
+        let [reply] = &outbox.take_lines()[..] else {
+            panic!("expected exactly one reply");
+        };
+        let reply: Message<Value> = serde_json::from_str(reply).unwrap();
+        reply.payload().clone()
+    }
+
+    #[test]
+    fn lin_kv() {
+        let mut net = Network::new(0);
+        let kv = net.add_service::<LinKv>("lin-kv");
+
+        let reply = net.call(&kv, json!({"type": "read", "key": "k"}));
+        assert_eq!(code(reply.payload()), ErrorCode::KeyDoesNotExist);
+        let reply = net.call(&kv, json!({"type": "cas", "key": "k", "from": 0, "to": 1}));
+        assert_eq!(code(reply.payload()), ErrorCode::KeyDoesNotExist);
+
+        let reply = net.call(&kv, json!({"type": "write", "key": "k", "value": 1}));
+        assert_eq!(reply.payload()["type"], "write_ok");
+        let reply = net.call(&kv, json!({"type": "cas", "key": "k", "from": 2, "to": 3}));
+        assert_eq!(code(reply.payload()), ErrorCode::PreConditionFailed);
+        let reply = net.call(&kv, json!({"type": "cas", "key": "k", "from": 1, "to": 3}));
+        assert_eq!(reply.payload()["type"], "cas_ok");
+        let reply = net.call(&kv, json!({"type": "read", "key": "k"}));
+        assert_eq!(reply.payload()["value"], 3);
+    }
+
+    #[test]
+    fn seq_kv() {
+        let outbox = Outbox::default();
+        let init = Init::Init {
+            node_id: NodeId::seq_kv(),
+            node_ids: Vec::new(),
+        };
+        let mut kv = <SeqKv as Node>::new(init, Output::new(outbox.clone()));
+
+        let reply = call(&mut kv, &outbox, "n0", json!({"type": "read", "key": "k"}));
+        assert_eq!(code(&reply), ErrorCode::KeyDoesNotExist);
+        for value in 1..=2 {
+            let write = json!({"type": "write", "key": "k", "value": value});
+            call(&mut kv, &outbox, "n0", write);
+        }
+        // cas is checked against the latest value, not a stale one
+        let cas = json!({"type": "cas", "key": "k", "from": 1, "to": 3});
+        assert_eq!(
+            code(&call(&mut kv, &outbox, "n1", cas)),
+            ErrorCode::PreConditionFailed
+        );
+
+        // a client always sees its own writes, the others may see older values
+        let read = json!({"type": "read", "key": "k"});
+        assert_eq!(call(&mut kv, &outbox, "n0", read.clone())["value"], 2);
+        let stale = (2..20)
+            .map(|client| call(&mut kv, &outbox, &format!("n{client}"), read.clone()))
+            .filter(|reply| reply["value"] != 2)
+            .count();
+        assert!(stale > 0, "no client read a stale value");
+    }
+
+    #[test]
+    fn lww_kv_loses_concurrent_writes() {
+        let mut net = Network::new(0);
+        let kv = net.add_service::<LwwKv>("lww-kv");
+        let sync = LwwKv::SYNC_INTERVAL * 2;
+
+        // both cas succeed when they hit different replicas, the older one is lost
+        let lost = (0..20).any(|_| {
+            net.call(&kv, json!({"type": "write", "key": "k", "value": 0}));
+            net.run_for(sync);
+            let first = net.call(&kv, json!({"type": "cas", "key": "k", "from": 0, "to": 1}));
+            let second = net.call(&kv, json!({"type": "cas", "key": "k", "from": 0, "to": 2}));
+            net.run_for(sync);
+            first.payload()["type"] == "cas_ok" && second.payload()["type"] == "cas_ok"
+        });
+        assert!(lost, "no two concurrent cas succeeded");
+        for _ in 0..5 {
+            let reply = net.call(&kv, json!({"type": "read", "key": "k"}));
+            assert_eq!(reply.payload()["value"], 2);
+        }
+    }
+}
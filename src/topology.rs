@@ -0,0 +1,181 @@
+//! Overlay networks computed from the `node_ids` of the init message,
+//! every node computes the same graph so no coordination is needed.
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    str::FromStr,
+};
+
+use crate::{rng::Rng, NodeId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Every node is a neighbor of every other node.
+    FullMesh,
+    /// Nodes are laid out row by row on a square, neighbors are up, down, left and right.
+    Grid,
+    /// Breadth first spanning tree of the grid rooted at its center,
+    /// no edge is redundant and the depth stays around the side of the grid.
+    SpanningTree,
+    /// The first node is connected to all others, which only talk to it.
+    HubAndSpoke,
+    /// Node `i` has the children `k * i + 1 ..= k * i + k`.
+    KaryTree(usize),
+    /// A ring with random chords added until every node has `k` neighbors where possible,
+    /// the ring keeps it connected.
+    RandomRegular(usize),
+}
+
+impl Strategy {
+    /// Neighbors of every node, in the shape of maelstrom's `topology` message.
+    pub fn topology(&self, node_ids: &[NodeId]) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut node_ids = node_ids.to_vec();
+        node_ids.sort();
+        node_ids.dedup();
+
+        let n = node_ids.len();
+        let mut edges = vec![BTreeSet::new(); n];
+        let connect = |edges: &mut Vec<BTreeSet<usize>>, a: usize, b: usize| {
+            if a != b {
+                edges[a].insert(b);
+                edges[b].insert(a);
+            }
+        };
+
+        match *self {
+            Self::FullMesh => {
+                for a in 0..n {
+                    for b in a + 1..n {
+                        connect(&mut edges, a, b);
+                    }
+                }
+            }
+            Self::Grid => {
+                let side = side(n);
+                for i in 0..n {
+                    if i % side + 1 < side && i + 1 < n {
+                        connect(&mut edges, i, i + 1);
+                    }
+                    if i + side < n {
+                        connect(&mut edges, i, i + side);
+                    }
+                }
+            }
+            Self::SpanningTree => {
+                let side = side(n);
+                let mut visited = vec![false; n];
+                let mut queue = VecDeque::new();
+                if n > 0 {
+                    let root = (n / side / 2) * side + side / 2;
+                    let root = root.min(n - 1);
+                    visited[root] = true;
+                    queue.push_back(root);
+                }
+                while let Some(i) = queue.pop_front() {
+                    let (row, col) = (i / side, i % side);
+                    let adjacent = [
+                        row.checked_sub(1).map(|row| row * side + col),
+                        Some((row + 1) * side + col),
+                        col.checked_sub(1).map(|col| row * side + col),
+                        (col + 1 < side).then_some(row * side + col + 1),
+                    ];
+                    for next in adjacent.into_iter().flatten().filter(|&next| next < n) {
+                        if !visited[next] {
+                            visited[next] = true;
+                            connect(&mut edges, i, next);
+                            queue.push_back(next);
+                        }
+                    }
+                }
+            }
+            Self::HubAndSpoke => {
+                for i in 1..n {
+                    connect(&mut edges, 0, i);
+                }
+            }
+            Self::KaryTree(k) => {
+                for i in 1..n {
+                    connect(&mut edges, i, (i - 1) / k.max(1));
+                }
+            }
+            Self::RandomRegular(k) => {
+                if n > 2 {
+                    for i in 0..n {
+                        connect(&mut edges, i, (i + 1) % n);
+                    }
+                } else if n == 2 {
+                    connect(&mut edges, 0, 1);
+                }
+                // seeded with the cluster size so all nodes pick the same chords
+                let mut rng = Rng::new(n as u64);
+                for _ in 0..n * k * 4 {
+                    let (a, b) = (rng.index(n.max(1)), rng.index(n.max(1)));
+                    if edges[a].len() < k && edges[b].len() < k && !edges[a].contains(&b) {
+                        connect(&mut edges, a, b);
+                    }
+                }
+            }
+        }
+
+        edges
+            .into_iter()
+            .enumerate()
+            .map(|(i, neighbors)| {
+                let neighbors = neighbors.into_iter().map(|j| node_ids[j].clone());
+                (node_ids[i].clone(), neighbors.collect())
+            })
+            .collect()
+    }
+
+    /// Neighbors of `node_id` only.
+    pub fn neighbors(&self, node_id: &NodeId, node_ids: &[NodeId]) -> Vec<NodeId> {
+        self.topology(node_ids).remove(node_id).unwrap_or_default()
+    }
+}
+
+// smallest side of a square that fits `n` nodes
+fn side(n: usize) -> usize {
+    let mut side = 1;
+    while side * side < n {
+        side += 1;
+    }
+    side
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseStrategyError(String);
+
+impl Display for ParseStrategyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown topology `{}`, expected one of mesh, grid, spanning-tree, hub, tree:<k>, random:<k>",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseStrategyError {}
+
+impl FromStr for Strategy {
+    type Err = ParseStrategyError;
+
+    /// Parses `mesh`, `grid`, `spanning-tree`, `hub`, `tree:<k>` and `random:<k>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseStrategyError(s.to_owned());
+        let (name, k) = match s.split_once(':') {
+            Some((name, k)) => (name, Some(k.parse::<usize>().map_err(|_| err())?)),
+            None => (s, None),
+        };
+        match (name, k) {
+            ("mesh", None) => Ok(Self::FullMesh),
+            ("grid", None) => Ok(Self::Grid),
+            ("spanning-tree", None) => Ok(Self::SpanningTree),
+            ("hub", None) => Ok(Self::HubAndSpoke),
+            ("tree", Some(k)) if k > 0 => Ok(Self::KaryTree(k)),
+            ("random", Some(k)) if k > 0 => Ok(Self::RandomRegular(k)),
+            _ => Err(err()),
+        }
+    }
+}
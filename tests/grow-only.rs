@@ -26,3 +26,28 @@ fn grow_only() {
     ]);
     assert!(cmd.spawn().unwrap().wait().unwrap().success())
 }
+
+#[test]
+#[serial]
+fn grow_only_crdt() {
+    const BIN: &str = std::env!("CARGO_BIN_EXE_grow-only");
+
+    let mut cmd = std::process::Command::new("bash");
+    cmd.env("GROW_ONLY_MODE", "crdt").args([
+        "maelstrom/maelstrom",
+        "test",
+        "-w",
+        "g-counter",
+        "--bin",
+        BIN,
+        "--node-count",
+        "3",
+        "--rate",
+        "100",
+        "--time-limit",
+        "20",
+        "--nemesis",
+        "partition",
+    ]);
+    assert!(cmd.spawn().unwrap().wait().unwrap().success())
+}
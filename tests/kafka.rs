@@ -0,0 +1,28 @@
+use serial_test::serial;
+
+#[test]
+#[serial]
+fn kafka() {
+    const BIN: &str = std::env!("CARGO_BIN_EXE_kafka");
+    println!("CWD: {}", std::env::current_dir().unwrap().display());
+    println!("BIN: {BIN}");
+
+    let mut cmd = std::process::Command::new("bash");
+    cmd.args([
+        "maelstrom/maelstrom",
+        "test",
+        "-w",
+        "kafka",
+        "--bin",
+        BIN,
+        "--node-count",
+        "2",
+        "--concurrency",
+        "2n",
+        "--rate",
+        "1000",
+        "--time-limit",
+        "20",
+    ]);
+    assert!(cmd.spawn().unwrap().wait().unwrap().success())
+}
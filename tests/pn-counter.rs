@@ -0,0 +1,28 @@
+use serial_test::serial;
+
+#[test]
+#[serial]
+fn pn_counter() {
+    const BIN: &str = std::env!("CARGO_BIN_EXE_pn-counter");
+    println!("CWD: {}", std::env::current_dir().unwrap().display());
+    println!("BIN: {BIN}");
+
+    let mut cmd = std::process::Command::new("bash");
+    cmd.args([
+        "maelstrom/maelstrom",
+        "test",
+        "-w",
+        "pn-counter",
+        "--bin",
+        BIN,
+        "--node-count",
+        "3",
+        "--rate",
+        "100",
+        "--time-limit",
+        "20",
+        "--nemesis",
+        "partition",
+    ]);
+    assert!(cmd.spawn().unwrap().wait().unwrap().success())
+}
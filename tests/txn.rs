@@ -0,0 +1,34 @@
+use serial_test::serial;
+
+#[test]
+#[serial]
+fn txn() {
+    const BIN: &str = std::env!("CARGO_BIN_EXE_txn");
+    println!("CWD: {}", std::env::current_dir().unwrap().display());
+    println!("BIN: {BIN}");
+
+    let mut cmd = std::process::Command::new("bash");
+    cmd.args([
+        "maelstrom/maelstrom",
+        "test",
+        "-w",
+        "txn-rw-register",
+        "--bin",
+        BIN,
+        "--node-count",
+        "2",
+        "--concurrency",
+        "2n",
+        "--time-limit",
+        "20",
+        "--rate",
+        "1000",
+        "--consistency-models",
+        "read-committed",
+        "--availability",
+        "total",
+        "--nemesis",
+        "partition",
+    ]);
+    assert!(cmd.spawn().unwrap().wait().unwrap().success())
+}